@@ -0,0 +1,1155 @@
+use std::io::{Error, ErrorKind};
+use std::collections::{HashMap, VecDeque};
+use std::borrow::Cow;
+use std::fmt;
+use std::iter::FromIterator;
+
+#[derive(Debug, PartialEq)]
+pub enum MatchingError {
+    StringNotFound,
+    InvalidSerialization
+}
+
+impl fmt::Display for MatchingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatchingError::StringNotFound => write!(f, "no rule matches the pattern"),
+            MatchingError::InvalidSerialization => write!(f, "invalid serialized pattern tree")
+        }
+    }
+}
+
+impl std::error::Error for MatchingError {}
+
+impl From<MatchingError> for Error {
+    fn from(e: MatchingError) -> Self {
+        let kind = match e {
+            MatchingError::StringNotFound => ErrorKind::NotFound,
+            MatchingError::InvalidSerialization => ErrorKind::InvalidData
+        };
+        Error::new(kind, e)
+    }
+}
+
+/// Conversion of the values stored in a tree to and from bytes, to (de)serialize it.
+pub trait ByteCodec: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decode a value from the start of `bytes`, returning it along with the number of bytes read.
+    fn decode(bytes: &[u8]) -> Option<(Self, usize)>;
+}
+
+macro_rules! impl_byte_codec_int {
+    ($($t:ty),*) => {
+        $(
+            impl ByteCodec for $t {
+                fn encode(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+                    const SIZE: usize = std::mem::size_of::<$t>();
+                    let mut buf = [0; SIZE];
+                    buf.copy_from_slice(bytes.get(..SIZE)?);
+                    Some((<$t>::from_le_bytes(buf), SIZE))
+                }
+            }
+        )*
+    }
+}
+
+impl_byte_codec_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl ByteCodec for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        out.extend_from_slice(self);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let (len, size) = u64::decode(bytes)?;
+        let end = size.checked_add(len as usize)?;
+        Some((bytes.get(size..end)?.to_vec(), end))
+    }
+}
+
+impl ByteCodec for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let (data, size) = Vec::<u8>::decode(bytes)?;
+        Some((String::from_utf8(data).ok()?, size))
+    }
+}
+
+static SERIALIZATION_MAGIC: &[u8] = b"AHO\x01";
+/// Longest key `AhoTree::deserialize` accepts: most methods of the tree recurse once per byte of
+/// the keys, so a crafted input could otherwise overflow the stack
+const MAX_DESERIALIZED_DEPTH: usize = 1024;
+
+/// A byte trie storing a value of type T for each inserted key.
+/// Once all the rules are inserted, `build` turns it into an Aho-Corasick automaton that can
+/// find every stored pattern inside a haystack in a single pass.
+#[derive(Debug, Clone)]
+pub struct AhoTree<T> {
+    content: u8,
+    value: Option<T>,
+    children: Vec<AhoTree<T>>,
+    // number of values stored in this subtree (including this node)
+    count: usize,
+    // wildcard transitions added by insert_glob, at most one of each kind
+    globs: Vec<(Glob, AhoTree<T>)>,
+    // fold ASCII letters to lowercase in keys and haystacks, only meaningful on the root
+    case_insensitive: bool,
+    // children reserved by the nodes of the first level created by insert_rule (see
+    // with_child_capacity), only meaningful on the root
+    child_capacity: usize,
+    // only ever set on the root, and reset by any modification of the tree
+    automaton: Option<Box<Automaton<T>>>
+}
+
+/// Factor by which the children capacity reserved by `AhoTree::with_child_capacity` shrinks at
+/// each level of the tree
+const CHILD_CAPACITY_DECAY: usize = 16;
+
+/// Wildcards of the patterns inserted with `AhoTree::insert_glob`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Glob {
+    /// `?`: exactly one byte
+    One,
+    /// `*`: any number of bytes (possibly zero) up to the next '/'
+    Any
+}
+
+/// A step of a glob pattern
+enum GlobToken {
+    Literal(u8),
+    Wildcard(Glob)
+}
+
+/// Split a glob pattern into its literal bytes and wildcards, a backslash escaping the byte
+/// following it.
+fn parse_glob(pattern: &[u8]) -> Vec<GlobToken> {
+    let mut tokens = Vec::with_capacity(pattern.len());
+    let mut bytes = pattern.iter();
+    while let Some(&c) = bytes.next() {
+        tokens.push(match c {
+            b'?' => GlobToken::Wildcard(Glob::One),
+            b'*' => GlobToken::Wildcard(Glob::Any),
+            // a trailing backslash stands for itself
+            b'\\' => GlobToken::Literal(*bytes.next().unwrap_or(&b'\\')),
+            c => GlobToken::Literal(c)
+        });
+    }
+    tokens
+}
+
+/// How `AhoTree::find_in` reports the matches it finds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchMode {
+    /// Every occurrence of every pattern, overlapping ones included, ordered by end offset.
+    Overlapping,
+    /// Non-overlapping matches, ordered by start offset: the scan picks the leftmost match, the
+    /// longest one if several patterns start there, and then resumes after its end, discarding
+    /// any match starting inside it. Two matches with the same start and length are necessarily
+    /// the same key, so there is no further tie to break.
+    LeftmostLongest
+}
+
+/// Filter the (start, end, state) `matches` of a scan, ordered by end offset, according to `mode`.
+fn select_matches(matches: &mut Vec<(usize, usize, usize)>, mode: MatchMode) {
+    if mode == MatchMode::LeftmostLongest {
+        matches.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        let mut last_end = 0;
+        matches.retain(|&(start, end, _)| {
+            if start < last_end {
+                false
+            } else {
+                last_end = end;
+                true
+            }
+        });
+    }
+}
+
+#[deprecated(note = "renamed to AhoTree")]
+#[allow(non_camel_case_types)]
+pub type aho_tree<T> = AhoTree<T>;
+
+/// A state of the automaton, mirroring a node of the tree.
+#[derive(Debug, Clone)]
+struct AutomatonState<T> {
+    transitions: Vec<(u8, usize)>,
+    /// The state matching the longest proper suffix of this state's path
+    fail: usize,
+    /// The closest state reachable through failure links that holds a value
+    output: Option<usize>,
+    parent: usize,
+    content: u8,
+    depth: usize,
+    value: Option<T>
+}
+
+#[derive(Debug, Clone)]
+struct Automaton<T> {
+    states: Vec<AutomatonState<T>>
+}
+
+impl<T> Automaton<T> {
+    fn goto(&self, state: usize, c: u8) -> Option<usize> {
+        let transitions = &self.states[state].transitions;
+        transitions.binary_search_by_key(&c, |x| x.0).ok().map(|pos| transitions[pos].1)
+    }
+
+    /// Follow the failure links until we find a state with a transition for `c`.
+    fn step(&self, mut state: usize, c: u8) -> usize {
+        loop {
+            if let Some(next) = self.goto(state, c) {
+                return next;
+            }
+            if state == 0 {
+                return 0;
+            }
+            state = self.states[state].fail;
+        }
+    }
+
+    /// Rebuild the path from the root to `state`.
+    fn key(&self, mut state: usize) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.states[state].depth);
+        while state != 0 {
+            key.push(self.states[state].content);
+            state = self.states[state].parent;
+        }
+        key.reverse();
+        key
+    }
+
+    /// Returns the first state holding a value that ends at `state` (the empty key at the root
+    /// never matches).
+    fn first_output(&self, state: usize) -> Option<usize> {
+        if state != 0 && self.states[state].value.is_some() {
+            Some(state)
+        } else {
+            self.states[state].output
+        }
+    }
+}
+
+impl<T> AhoTree<T> {
+    pub fn new() -> Self {
+        AhoTree::with_content(0)
+    }
+
+    /// Create a tree whose root has room for `n` children (at most 256, one per byte), and whose
+    /// nodes reserve room for theirs as they are created, a sixteenth of their parent's at each
+    /// level down (as the keys spread out).
+    /// Use this before inserting many keys, so that the busy nodes near the root don't keep
+    /// reallocating their children.
+    pub fn with_child_capacity(n: usize) -> Self {
+        let n = n.min(256);
+        let mut t = AhoTree::with_capacity(0, n);
+        t.child_capacity = n/CHILD_CAPACITY_DECAY;
+        t
+    }
+
+    /// Create a tree matching ASCII letters case-insensitively, e.g. for header names.
+    pub fn new_ascii_ci() -> Self {
+        let mut t = AhoTree::new();
+        t.case_insensitive = true;
+        t
+    }
+
+    /// Toggle case-insensitive matching of ASCII letters.
+    /// Keys are folded when inserted, so this should be set before inserting any rule.
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+        self.automaton = None;
+    }
+
+    fn fold<'a>(&self, arr: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.case_insensitive {
+            Cow::Owned(arr.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(arr)
+        }
+    }
+
+    fn fold_byte(&self, c: u8) -> u8 {
+        if self.case_insensitive {
+            c.to_ascii_lowercase()
+        } else {
+            c
+        }
+    }
+
+    fn with_content(content: u8) -> Self {
+        AhoTree::with_capacity(content, 0)
+    }
+
+    fn with_capacity(content: u8, capacity: usize) -> Self {
+        AhoTree {
+            content,
+            value: None,
+            children: Vec::with_capacity(capacity),
+            count: 0,
+            globs: Vec::new(),
+            case_insensitive: false,
+            child_capacity: 0,
+            automaton: None
+        }
+    }
+
+    /// Children are kept sorted by content, so we can binary search them.
+    fn child_pos(&self, c: u8) -> Result<usize, usize> {
+        self.children.binary_search_by_key(&c, |x| x.content)
+    }
+
+    fn child(&self, c: u8) -> Option<&AhoTree<T>> {
+        self.child_pos(c).ok().map(|pos| &self.children[pos])
+    }
+
+    /// Insert (or overwrite) the value associated with `arr`, returning the value it replaced if
+    /// the key was already there.
+    /// An empty `arr` stores the value on the root itself.
+    /// This invalidates the automaton, so `build` must be called again before using `find_in`.
+    pub fn insert_rule(&mut self, arr: &[u8], value: T) -> Option<T> {
+        let arr = self.fold(arr);
+        let capacity = self.child_capacity;
+        self.insert(&arr, value, capacity)
+    }
+
+    /// Insert every rule of `rules`, and return the keys that already had a value (which was
+    /// overwritten), in the order they were met. A key inserted twice by `rules` is reported too.
+    pub fn insert_many<I: IntoIterator<Item = (Vec<u8>, T)>>(&mut self, rules: I) -> Vec<Vec<u8>> {
+        rules.into_iter()
+            .filter_map(|(key, value)| self.insert_rule(&key, value).map(|_| key))
+            .collect()
+    }
+
+    /// Insert `value` at `arr`, returning the value previously stored there.
+    /// The nodes created on the way reserve room for `capacity` children.
+    fn insert(&mut self, arr: &[u8], value: T, capacity: usize) -> Option<T> {
+        self.automaton = None;
+        let res = match arr.split_first() {
+            None => self.value.replace(value),
+            Some((&first, rest)) => {
+                let pos = match self.child_pos(first) {
+                    Ok(pos) => pos,
+                    Err(pos) => {
+                        self.children.insert(pos, AhoTree::with_capacity(first, capacity));
+                        pos
+                    }
+                };
+                self.children[pos].insert(rest, value, capacity/CHILD_CAPACITY_DECAY)
+            }
+        };
+        if res.is_none() {
+            self.count += 1;
+        }
+        res
+    }
+
+    /// Insert (or overwrite) the value associated with the glob `pattern`, in which `?` matches
+    /// any single byte and `*` any run of bytes (possibly empty) up to the next '/', e.g.
+    /// `/files/*/raw`. A backslash makes the byte following it literal: `\*` matches `*` only.
+    /// Patterns with wildcards are only ever matched by `search`, which tries them when the
+    /// literal keys fail. They aren't counted by `len`, and the automaton, iteration,
+    /// serialization and merge of case-insensitive trees ignore them.
+    pub fn insert_glob(&mut self, pattern: &[u8], value: T) {
+        let pattern = self.fold(pattern);
+        self.insert_tokens(&parse_glob(&pattern), value);
+    }
+
+    fn insert_tokens(&mut self, tokens: &[GlobToken], value: T) {
+        match tokens.split_first() {
+            None => {
+                self.automaton = None;
+                if self.value.replace(value).is_none() {
+                    self.count += 1;
+                }
+            },
+            Some((GlobToken::Literal(c), rest)) => {
+                self.automaton = None;
+                let pos = match self.child_pos(*c) {
+                    Ok(pos) => pos,
+                    Err(pos) => {
+                        self.children.insert(pos, AhoTree::with_content(*c));
+                        pos
+                    }
+                };
+                self.children[pos].insert_tokens(rest, value);
+                self.count = self.value.is_some() as usize + self.children.iter().map(|x| x.count).sum::<usize>();
+            },
+            Some((GlobToken::Wildcard(glob), rest)) => {
+                let pos = match self.globs.iter().position(|(x, _)| x == glob) {
+                    Some(pos) => pos,
+                    None => {
+                        self.globs.push((*glob, AhoTree::with_content(0)));
+                        self.globs.len()-1
+                    }
+                };
+                self.globs[pos].1.insert_tokens(rest, value);
+            }
+        }
+    }
+
+    /// Remove the value stored for `arr`, returning it if there was one.
+    /// Nodes left without value nor children are pruned on the way back up.
+    pub fn remove_rule(&mut self, arr: &[u8]) -> Option<T> {
+        let arr = self.fold(arr);
+        self.remove(&arr)
+    }
+
+    fn remove(&mut self, arr: &[u8]) -> Option<T> {
+        let res = match arr.split_first() {
+            None => self.value.take(),
+            Some((&first, rest)) => {
+                let pos = self.child_pos(first).ok()?;
+                let res = self.children[pos].remove(rest);
+                let child = &self.children[pos];
+                if child.value.is_none() && child.children.is_empty() && child.globs.is_empty() {
+                    self.children.remove(pos);
+                }
+                res
+            }
+        };
+        if res.is_some() {
+            self.count -= 1;
+            self.automaton = None;
+        }
+        res
+    }
+
+    /// Apply `f` to the value stored for `arr` (`None` if there is none) and store its result, in
+    /// a single descent. Returning `None` clears the value.
+    pub fn update<F: FnOnce(Option<T>) -> Option<T>>(&mut self, arr: &[u8], f: F) {
+        let arr = self.fold(arr);
+        self.update_node(&arr, f);
+    }
+
+    fn update_node<F: FnOnce(Option<T>) -> Option<T>>(&mut self, arr: &[u8], f: F) {
+        self.automaton = None;
+        match arr.split_first() {
+            None => self.value = f(self.value.take()),
+            Some((&first, rest)) => {
+                let pos = match self.child_pos(first) {
+                    Ok(pos) => pos,
+                    Err(pos) => {
+                        self.children.insert(pos, AhoTree::with_content(first));
+                        pos
+                    }
+                };
+                self.children[pos].update_node(rest, f);
+                let child = &self.children[pos];
+                if child.value.is_none() && child.children.is_empty() && child.globs.is_empty() {
+                    self.children.remove(pos);
+                }
+            }
+        }
+        self.count = self.value.is_some() as usize + self.children.iter().map(|x| x.count).sum::<usize>();
+    }
+
+    /// Add every rule of `other` to this tree. When a key exists in both trees, the value from
+    /// `other` wins.
+    pub fn merge(&mut self, other: AhoTree<T>) {
+        self.automaton = None;
+        if self.case_insensitive && !other.case_insensitive {
+            // the keys of other must be folded first
+            let mut entries = Vec::new();
+            other.into_entries(&mut Vec::new(), &mut entries);
+            for (key, val) in entries {
+                self.insert_rule(&key, val);
+            }
+        } else {
+            self.merge_node(other);
+        }
+    }
+
+    /// Move every (key, value) of the subtree to `res`, `key` being the path to this node.
+    fn into_entries(self, key: &mut Vec<u8>, res: &mut Vec<(Vec<u8>, T)>) {
+        if let Some(val) = self.value {
+            res.push((key.clone(), val));
+        }
+        for child in self.children {
+            key.push(child.content);
+            child.into_entries(key, res);
+            key.pop();
+        }
+    }
+
+    fn merge_node(&mut self, other: AhoTree<T>) {
+        if other.value.is_some() {
+            self.value = other.value;
+        }
+        for child in other.children {
+            match self.child_pos(child.content) {
+                Ok(pos) => self.children[pos].merge_node(child),
+                Err(pos) => self.children.insert(pos, child)
+            }
+        }
+        for (glob, node) in other.globs {
+            match self.globs.iter().position(|(x, _)| *x == glob) {
+                Some(pos) => self.globs[pos].1.merge_node(node),
+                None => self.globs.push((glob, node))
+            }
+        }
+        self.count = self.value.is_some() as usize + self.children.iter().map(|x| x.count).sum::<usize>();
+    }
+
+    /// Number of keys stored in the tree.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the number of nodes (root included) and the height of the subtree.
+    fn shape(&self) -> (usize, usize) {
+        self.children.iter()
+            .map(|x| x.shape())
+            .fold((1, 0), |(nodes, height), (child_nodes, child_height)| {
+                (nodes+child_nodes, height.max(child_height+1))
+            })
+    }
+
+    /// Number of nodes in the tree, including the root and the nodes holding no value.
+    pub fn node_count(&self) -> usize {
+        self.shape().0
+    }
+
+    /// Length in bytes of the longest path from the root to a leaf.
+    pub fn height(&self) -> usize {
+        self.shape().1
+    }
+
+    /// Descend to the node matching exactly `arr`, if any.
+    fn find_node(&self, arr: &[u8]) -> Option<&AhoTree<T>> {
+        let mut node = self;
+        for &c in arr {
+            node = node.child(c)?;
+        }
+        Some(node)
+    }
+
+    /// Number of keys starting with `prefix` (including `prefix` itself), without enumerating
+    /// them: every node keeps the number of keys below it.
+    /// Wildcard patterns are not counted.
+    pub fn prefix_count(&self, prefix: &[u8]) -> usize {
+        self.find_node(&self.fold(prefix)).map_or(0, |node| node.count)
+    }
+
+    /// Borrow the value stored for exactly `arr`, if any.
+    pub fn get_ref(&self, arr: &[u8]) -> Option<&T> {
+        self.find_node(&self.fold(arr))?.value.as_ref()
+    }
+
+    /// Find the longest stored key that is a prefix of `input`, returning its length and a
+    /// reference to its value.
+    /// E.g. with `/`, `/api` and `/api/v1` registered, `/api/v1/users` matches `/api/v1`.
+    pub fn longest_prefix_match_ref(&self, input: &[u8]) -> Option<(usize, &T)> {
+        let mut node = self;
+        let mut best = self.value.as_ref().map(|x| (0, x));
+        for (i, &c) in input.iter().enumerate() {
+            node = match node.child(self.fold_byte(c)) {
+                Some(x) => x,
+                None => break
+            };
+            if let Some(val) = &node.value {
+                best = Some((i+1, val));
+            }
+        }
+        best
+    }
+}
+
+impl<T: Clone> AhoTree<T> {
+    fn search_children(&self, arr: &[u8]) -> Result<Option<T>, MatchingError> {
+        let res = match arr.split_first() {
+            None => Ok(self.value.clone()),
+            Some((&first, rest)) => {
+                match self.child(first) {
+                    Some(x) => x.search_children(rest),
+                    None => Err(MatchingError::StringNotFound)
+                }
+            }
+        };
+        if let Ok(Some(_)) = res {
+            return res;
+        }
+        // the literal path failed, backtrack through the wildcards
+        for (glob, node) in &self.globs {
+            let found = match glob {
+                Glob::One if arr.is_empty() => None,
+                Glob::One => node.search_children(&arr[1..]).ok().flatten(),
+                Glob::Any => {
+                    let segment = arr.iter().position(|c| *c == b'/').unwrap_or(arr.len());
+                    (0..=segment).find_map(|skip| node.search_children(&arr[skip..]).ok().flatten())
+                }
+            };
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        res
+    }
+
+    /// Retrieve the value stored for exactly `arr`, if any.
+    /// This is the recommended lookup: whether the path doesn't exist or exists without holding
+    /// a value, the answer is simply `None`.
+    pub fn get(&self, arr: &[u8]) -> Option<T> {
+        self.get_ref(arr).cloned()
+    }
+
+    /// Retrieve the value stored for exactly `arr`, telling apart keys that aren't a path in the
+    /// tree (`Err(StringNotFound)`) from those that are but hold no value (`Ok(None)`).
+    /// Prefer `get` unless that distinction matters.
+    /// The empty key is stored at the root, so searching for an empty slice returns its value,
+    /// if any.
+    /// The patterns inserted with `insert_glob` are tried when no literal key matches.
+    pub fn search(&self, arr: &[u8]) -> Result<Option<T>, MatchingError> {
+        self.search_children(&self.fold(arr))
+    }
+
+    /// Append every (key, value) of the subtree to `res`, `key` being the path to this node.
+    fn collect(&self, key: &mut Vec<u8>, res: &mut Vec<(Vec<u8>, T)>) {
+        if let Some(val) = &self.value {
+            res.push((key.clone(), val.clone()));
+        }
+        for child in &self.children {
+            key.push(child.content);
+            child.collect(key, res);
+            key.pop();
+        }
+    }
+
+    /// Find the longest stored key that is a prefix of `input`, returning its length and value.
+    /// E.g. with `/`, `/api` and `/api/v1` registered, `/api/v1/users` matches `/api/v1`.
+    pub fn longest_prefix_match(&self, input: &[u8]) -> Option<(usize, T)> {
+        self.longest_prefix_match_ref(input).map(|(len, val)| (len, val.clone()))
+    }
+
+    /// Retrieve all the values whose keys start with `prefix`, along with their full keys.
+    /// In case-insensitive mode, the keys are returned lowercased.
+    pub fn search_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, T)> {
+        let mut res = Vec::new();
+        let prefix = self.fold(prefix);
+        if let Some(node) = self.find_node(&prefix) {
+            node.collect(&mut prefix.to_vec(), &mut res);
+        }
+        res
+    }
+
+    /// Iterate lazily over every (key, value) of the tree, in depth-first order.
+    pub fn iter(&self) -> AhoIter<'_, T> {
+        AhoIter {
+            stack: vec![(self, 0)],
+            key: Vec::new(),
+            pending: Some(self)
+        }
+    }
+
+    /// Create a tree holding all the `rules`, with its automaton already built.
+    pub fn from_rules<I: IntoIterator<Item = (Vec<u8>, T)>>(rules: I) -> Self {
+        let rules = rules.into_iter();
+        let mut t = AhoTree::with_child_capacity(rules.size_hint().0);
+        for (key, val) in rules {
+            t.insert_rule(&key, val);
+        }
+        t.build();
+        t
+    }
+
+    /// Compute the failure links of every node by walking the tree breadth-first.
+    /// Must be called after the last `insert_rule` and before `find_in`.
+    pub fn build(&mut self) {
+        let mut states = vec![AutomatonState {
+            transitions: Vec::with_capacity(self.children.len()),
+            fail: 0,
+            output: None,
+            parent: 0,
+            content: 0,
+            depth: 0,
+            value: self.value.clone()
+        }];
+
+        let mut queue = VecDeque::new();
+        queue.push_back((&*self, 0));
+        while let Some((node, state)) = queue.pop_front() {
+            for child in &node.children {
+                // the failure link of the parent is shallower, and has thus already been processed
+                let fail = if state == 0 {
+                    0
+                } else {
+                    let mut f = states[state].fail;
+                    loop {
+                        let transitions = &states[f].transitions;
+                        if let Ok(pos) = transitions.binary_search_by_key(&child.content, |x| x.0) {
+                            break transitions[pos].1;
+                        }
+                        if f == 0 {
+                            break 0;
+                        }
+                        f = states[f].fail;
+                    }
+                };
+                let output = if fail != 0 && states[fail].value.is_some() {
+                    Some(fail)
+                } else {
+                    states[fail].output
+                };
+
+                let id = states.len();
+                states.push(AutomatonState {
+                    transitions: Vec::with_capacity(child.children.len()),
+                    fail,
+                    output,
+                    parent: state,
+                    content: child.content,
+                    depth: states[state].depth+1,
+                    value: child.value.clone()
+                });
+                states[state].transitions.push((child.content, id));
+                queue.push_back((child, id));
+            }
+        }
+
+        self.automaton = Some(Box::new(Automaton {
+            states
+        }));
+    }
+
+    /// Scan `haystack` once and report the occurrences of the stored patterns, along with the
+    /// offset at which they start, according to `mode` (see `MatchMode`).
+    /// Returns an empty Vec if the automaton isn't built (see `build`).
+    pub fn find_in(&self, haystack: &[u8], mode: MatchMode) -> Vec<(usize, T)> {
+        let automaton = match &self.automaton {
+            Some(x) => x,
+            None => return Vec::new()
+        };
+
+        // (start, end, state) of every match, ordered by end offset
+        let mut matches = Vec::new();
+        self.scan(automaton, 0, haystack, |end, s| matches.push((end-automaton.states[s].depth, end, s)));
+
+        select_matches(&mut matches, mode);
+        matches.into_iter()
+            .map(|(start, _, s)| (start, automaton.states[s].value.clone().unwrap()))
+            .collect()
+    }
+
+    /// Return the first match completed while scanning `haystack` (the one ending first, the
+    /// longest if several end at the same offset) with its start offset, without scanning any
+    /// further. This is much cheaper than `find_in` when only the presence of a pattern matters.
+    /// Note that a match starting before it may exist: with `abcd` and `bc` stored, this finds
+    /// `bc` in `abcd`, as it ends first.
+    /// Returns None if the automaton isn't built (see `build`).
+    pub fn find_first(&self, haystack: &[u8]) -> Option<(usize, T)> {
+        let automaton = self.automaton.as_ref()?;
+        let mut state = 0;
+        for (i, &c) in haystack.iter().enumerate() {
+            state = automaton.step(state, self.fold_byte(c));
+            // the first output is the deepest state, and thus the longest match
+            if let Some(s) = automaton.first_output(state) {
+                let s = &automaton.states[s];
+                return Some((i+1-s.depth, s.value.clone().unwrap()));
+            }
+        }
+        None
+    }
+
+    /// Feed `haystack` to the automaton starting from `state`, calling `on_match` with the end
+    /// offset (exclusive) in `haystack` and the (value-holding) state of every match.
+    /// Returns the final state.
+    fn scan<F: FnMut(usize, usize)>(&self, automaton: &Automaton<T>, mut state: usize, haystack: &[u8], mut on_match: F) -> usize {
+        for (i, &c) in haystack.iter().enumerate() {
+            state = automaton.step(state, self.fold_byte(c));
+            let mut out = automaton.first_output(state);
+            while let Some(s) = out {
+                on_match(i+1, s);
+                out = automaton.states[s].output;
+            }
+        }
+        state
+    }
+
+    /// Count how many times each stored pattern occurs in `haystack`, overlapping occurrences
+    /// included. Patterns that never occur (as well as the empty key) are left out of the map.
+    /// Returns an empty map if the automaton isn't built (see `build`).
+    pub fn count_matches(&self, haystack: &[u8]) -> HashMap<Vec<u8>, usize> {
+        let mut res = HashMap::new();
+        let automaton = match &self.automaton {
+            Some(x) => x,
+            None => return res
+        };
+
+        let mut counts = vec![0; automaton.states.len()];
+        self.scan(automaton, 0, haystack, |_, s| counts[s] += 1);
+        for (id, &count) in counts.iter().enumerate().filter(|x| *x.1 > 0) {
+            res.insert(automaton.key(id), count);
+        }
+        res
+    }
+
+    /// Convert the tree to a read-only `PackedAho`, building the automaton if needed.
+    pub fn into_packed(mut self) -> PackedAho<T> {
+        if self.automaton.is_none() {
+            self.build();
+        }
+        PackedAho::from_automaton(*self.automaton.unwrap(), self.case_insensitive)
+    }
+
+    /// Create a scanner to search for patterns in a stream fed chunk by chunk.
+    pub fn scanner(&self) -> AhoScanner<'_, T> {
+        AhoScanner {
+            tree: self,
+            state: 0,
+            offset: 0
+        }
+    }
+}
+
+/// Serialized format: the magic, a byte for the case-insensitive flag and then the nodes in
+/// depth-first order, each node being its content byte, a byte telling whether it holds a value,
+/// the encoded value if any and its number of children (as a little-endian u32).
+impl<T: ByteCodec> AhoTree<T> {
+    fn serialize_node(&self, out: &mut Vec<u8>) {
+        out.push(self.content);
+        match &self.value {
+            Some(val) => {
+                out.push(1);
+                val.encode(out);
+            },
+            None => out.push(0)
+        }
+        (self.children.len() as u32).encode(out);
+        for child in &self.children {
+            child.serialize_node(out);
+        }
+    }
+
+    /// Save the structure of the tree (but not its automaton) to a byte buffer.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = SERIALIZATION_MAGIC.to_vec();
+        out.push(self.case_insensitive as u8);
+        self.serialize_node(&mut out);
+        out
+    }
+
+    /// Read a node without its children, returning it with the number of children that follow.
+    fn deserialize_node(bytes: &[u8], pos: &mut usize) -> Result<(AhoTree<T>, u32), MatchingError> {
+        let header = bytes.get(*pos..*pos+2).ok_or(MatchingError::InvalidSerialization)?;
+        *pos += 2;
+        let mut node = AhoTree::with_content(header[0]);
+        match header[1] {
+            0 => {},
+            1 => {
+                let (val, size) = T::decode(&bytes[*pos..]).ok_or(MatchingError::InvalidSerialization)?;
+                *pos += size;
+                node.value = Some(val);
+                node.count = 1;
+            },
+            _ => return Err(MatchingError::InvalidSerialization)
+        }
+
+        let (children, size) = u32::decode(&bytes[*pos..]).ok_or(MatchingError::InvalidSerialization)?;
+        *pos += size;
+        Ok((node, children))
+    }
+
+    /// Rebuild a tree saved by `serialize`. The automaton must be built again with `build`.
+    /// Trees with keys longer than MAX_DESERIALIZED_DEPTH (1024 bytes) are rejected.
+    pub fn deserialize(bytes: &[u8]) -> Result<AhoTree<T>, MatchingError> {
+        let len = SERIALIZATION_MAGIC.len();
+        if bytes.len() < len+1 || &bytes[..len] != SERIALIZATION_MAGIC {
+            return Err(MatchingError::InvalidSerialization);
+        }
+        let case_insensitive = match bytes[len] {
+            0 => false,
+            1 => true,
+            _ => return Err(MatchingError::InvalidSerialization)
+        };
+
+        let mut pos = len+1;
+        // the nodes whose children are being read, with the number of children they still expect:
+        // the depth of the tree comes from the input, so no recursion here
+        let mut stack = vec![AhoTree::deserialize_node(bytes, &mut pos)?];
+        let mut tree = loop {
+            let left = &mut stack.last_mut().unwrap().1;
+            if *left > 0 {
+                *left -= 1;
+                // the other methods of the tree do recurse
+                if stack.len() > MAX_DESERIALIZED_DEPTH {
+                    return Err(MatchingError::InvalidSerialization);
+                }
+                stack.push(AhoTree::deserialize_node(bytes, &mut pos)?);
+                continue;
+            }
+            let (child, _) = stack.pop().unwrap();
+            let node = match stack.last_mut() {
+                Some((node, _)) => node,
+                None => break child
+            };
+            // children must be sorted for the lookups to work
+            if let Some(last) = node.children.last() {
+                if last.content >= child.content {
+                    return Err(MatchingError::InvalidSerialization);
+                }
+            }
+            node.count += child.count;
+            node.children.push(child);
+        };
+        if pos != bytes.len() {
+            return Err(MatchingError::InvalidSerialization);
+        }
+        tree.case_insensitive = case_insensitive;
+        Ok(tree)
+    }
+}
+
+const PACKED_NONE: u32 = u32::MAX;
+
+/// A read-only Aho-Corasick automaton stored as a double-array trie: the transition from `s` on
+/// the byte `c` leads to the slot `base[s]+c`, provided `check[base[s]+c] == s`.
+/// The automaton is thus held in a few flat arrays instead of a tree of heap-allocated nodes,
+/// which is much more compact and cache-friendly for large rule sets.
+#[derive(Debug, Clone)]
+pub struct PackedAho<T> {
+    base: Vec<u32>,
+    check: Vec<u32>,
+    fail: Vec<u32>,
+    output: Vec<u32>,
+    depth: Vec<u32>,
+    values: Vec<Option<T>>,
+    case_insensitive: bool
+}
+
+impl<T> PackedAho<T> {
+    fn from_automaton(automaton: Automaton<T>, case_insensitive: bool) -> Self {
+        assert!(automaton.states.len() < (PACKED_NONE-256) as usize, "too many states to pack");
+
+        let mut packed = PackedAho {
+            base: vec![0],
+            check: vec![PACKED_NONE],
+            fail: Vec::new(),
+            output: Vec::new(),
+            depth: Vec::new(),
+            values: Vec::new(),
+            case_insensitive
+        };
+
+        // place the children of each state, the states being numbered in breadth-first order
+        let mut slots = vec![0; automaton.states.len()];
+        let mut first_free = 1;
+        for (id, state) in automaton.states.iter().enumerate() {
+            let first = match state.transitions.first() {
+                Some(x) => x.0 as usize,
+                None => continue
+            };
+            let mut base = if first_free > first { first_free-first } else { 1 };
+            loop {
+                let fits = state.transitions.iter().all(|&(c, _)| {
+                    packed.check.get(base+c as usize).is_none_or(|&x| x == PACKED_NONE)
+                });
+                if fits {
+                    break;
+                }
+                base += 1;
+            }
+
+            let max = base+state.transitions.last().unwrap().0 as usize;
+            if max >= packed.check.len() {
+                packed.check.resize(max+1, PACKED_NONE);
+                packed.base.resize(max+1, 0);
+            }
+            packed.base[slots[id]] = base as u32;
+            for &(c, child) in &state.transitions {
+                packed.check[base+c as usize] = slots[id] as u32;
+                slots[child] = base+c as usize;
+            }
+            while first_free < packed.check.len() && packed.check[first_free] != PACKED_NONE {
+                first_free += 1;
+            }
+        }
+
+        let len = packed.check.len();
+        packed.fail.resize(len, 0);
+        packed.output.resize(len, PACKED_NONE);
+        packed.depth.resize(len, 0);
+        packed.values.resize_with(len, || None);
+        for (id, state) in automaton.states.into_iter().enumerate() {
+            let slot = slots[id];
+            packed.fail[slot] = slots[state.fail] as u32;
+            packed.output[slot] = state.output.map_or(PACKED_NONE, |x| slots[x] as u32);
+            packed.depth[slot] = state.depth as u32;
+            packed.values[slot] = state.value;
+        }
+        packed
+    }
+
+    fn fold_byte(&self, c: u8) -> u8 {
+        if self.case_insensitive {
+            c.to_ascii_lowercase()
+        } else {
+            c
+        }
+    }
+
+    fn goto(&self, state: usize, c: u8) -> Option<usize> {
+        let next = self.base[state] as usize + c as usize;
+        match self.check.get(next) {
+            Some(&x) if x as usize == state => Some(next),
+            _ => None
+        }
+    }
+
+    fn step(&self, mut state: usize, c: u8) -> usize {
+        loop {
+            if let Some(next) = self.goto(state, c) {
+                return next;
+            }
+            if state == 0 {
+                return 0;
+            }
+            state = self.fail[state] as usize;
+        }
+    }
+
+    /// Borrow the value stored for exactly `arr`, if any.
+    pub fn get_ref(&self, arr: &[u8]) -> Option<&T> {
+        let mut state = 0;
+        for &c in arr {
+            state = self.goto(state, self.fold_byte(c))?;
+        }
+        self.values[state].as_ref()
+    }
+
+    /// Number of slots of the arrays, some of them being unused.
+    pub fn slot_count(&self) -> usize {
+        self.check.len()
+    }
+}
+
+impl<T: Clone> PackedAho<T> {
+    /// Retrieve the value stored for exactly `arr`, if any.
+    pub fn get(&self, arr: &[u8]) -> Option<T> {
+        self.get_ref(arr).cloned()
+    }
+
+    /// Same as `AhoTree::find_in`.
+    pub fn find_in(&self, haystack: &[u8], mode: MatchMode) -> Vec<(usize, T)> {
+        let mut matches = Vec::new();
+        let mut state = 0;
+        for (i, &c) in haystack.iter().enumerate() {
+            state = self.step(state, self.fold_byte(c));
+            let mut out = if state != 0 && self.values[state].is_some() {
+                state as u32
+            } else {
+                self.output[state]
+            };
+            while out != PACKED_NONE {
+                let s = out as usize;
+                matches.push((i+1-self.depth[s] as usize, i+1, s));
+                out = self.output[s];
+            }
+        }
+
+        select_matches(&mut matches, mode);
+        matches.into_iter()
+            .map(|(start, _, s)| (start, self.values[s].clone().unwrap()))
+            .collect()
+    }
+}
+
+/// A streaming search over a built `AhoTree`: the automaton state is carried over between
+/// chunks so a pattern split across two calls to `push` is still found.
+pub struct AhoScanner<'a, T> {
+    tree: &'a AhoTree<T>,
+    state: usize,
+    // number of bytes pushed so far
+    offset: usize
+}
+
+impl<'a, T: Clone> AhoScanner<'a, T> {
+    /// Scan the next chunk of the stream, returning the matches ending in it along with their
+    /// start offset from the beginning of the stream.
+    /// Returns an empty Vec if the automaton isn't built (see `AhoTree::build`).
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<(usize, T)> {
+        let mut res = Vec::new();
+        if let Some(automaton) = &self.tree.automaton {
+            let offset = self.offset;
+            self.state = self.tree.scan(automaton, self.state, chunk, |end, s| {
+                let s = &automaton.states[s];
+                res.push((offset+end-s.depth, s.value.clone().unwrap()));
+            });
+        }
+        self.offset += chunk.len();
+        res
+    }
+
+    /// Go back to the start of a new stream.
+    pub fn reset(&mut self) {
+        self.state = 0;
+        self.offset = 0;
+    }
+}
+
+/// Depth-first iterator over the (key, value) pairs of an `AhoTree`.
+pub struct AhoIter<'a, T> {
+    // the path to the current node, along with the index of the next child to visit for each node
+    stack: Vec<(&'a AhoTree<T>, usize)>,
+    key: Vec<u8>,
+    // node whose value hasn't been yielded yet
+    pending: Option<&'a AhoTree<T>>
+}
+
+impl<'a, T: Clone> Iterator for AhoIter<'a, T> {
+    type Item = (Vec<u8>, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(node) = self.pending.take() {
+                if let Some(val) = &node.value {
+                    return Some((self.key.clone(), val.clone()));
+                }
+            }
+
+            let (node, next_child) = self.stack.last_mut()?;
+            if let Some(child) = node.children.get(*next_child) {
+                *next_child += 1;
+                self.key.push(child.content);
+                self.stack.push((child, 0));
+                self.pending = Some(child);
+            } else {
+                self.stack.pop();
+                self.key.pop();
+            }
+        }
+    }
+}
+
+impl<'a, T: Clone> IntoIterator for &'a AhoTree<T> {
+    type Item = (Vec<u8>, T);
+    type IntoIter = AhoIter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: Clone> FromIterator<(Vec<u8>, T)> for AhoTree<T> {
+    fn from_iter<I: IntoIterator<Item = (Vec<u8>, T)>>(iter: I) -> Self {
+        AhoTree::from_rules(iter)
+    }
+}
+
+impl<T> Default for AhoTree<T> {
+    fn default() -> Self {
+        AhoTree::new()
+    }
+}
@@ -1,46 +1,390 @@
 use nix::sys::mman;
+use std::fs::OpenOptions;
 use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::{ptr, slice};
+#[cfg(debug_assertions)]
+use std::sync::Arc;
+#[cfg(debug_assertions)]
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 /// An unsafe block to store an array of elements and provide interior mutability for them.
 pub struct BackingStore<T> {
     len: usize,
-    data: *mut T
+    data: *mut T,
+    // Whether the elements live in a file rather than in anonymous memory
+    file_backed: bool,
+    // Debug builds keep track of the slots holding an element, to catch reads of uninitialized
+    // slots (and double moves)
+    #[cfg(debug_assertions)]
+    initialized: Arc<InitBitmap>
 }
 
+#[cfg(debug_assertions)]
+#[derive(Debug)]
+struct InitBitmap {
+    words: Vec<AtomicUsize>
+}
+
+#[cfg(debug_assertions)]
+impl InitBitmap {
+    const BITS: usize = usize::BITS as usize;
+
+    fn new(len: usize, initialized: bool) -> InitBitmap {
+        let word = if initialized { usize::MAX } else { 0 };
+        InitBitmap {
+            words: (0..len.div_ceil(InitBitmap::BITS)).map(|_| AtomicUsize::new(word)).collect()
+        }
+    }
+
+    fn is_set(&self, pos: usize) -> bool {
+        self.words[pos/InitBitmap::BITS].load(Ordering::Acquire) & (1 << (pos%InitBitmap::BITS)) != 0
+    }
+
+    fn mark(&self, pos: usize, initialized: bool) {
+        let mask = 1 << (pos%InitBitmap::BITS);
+        if initialized {
+            self.words[pos/InitBitmap::BITS].fetch_or(mask, Ordering::AcqRel);
+        } else {
+            self.words[pos/InitBitmap::BITS].fetch_and(!mask, Ordering::AcqRel);
+        }
+    }
+
+    fn resized(&self, len: usize) -> InitBitmap {
+        let bitmap = InitBitmap::new(len, false);
+        for (new, old) in bitmap.words.iter().zip(self.words.iter()) {
+            new.store(old.load(Ordering::Acquire), Ordering::Release);
+        }
+        bitmap
+    }
+}
+
+#[derive(Debug)]
 pub struct AllocationFailed {}
 
-//unsafe impl<T> Send for BackingStore<T> {}
+#[derive(Debug, PartialEq)]
+pub struct OutOfBounds {}
+
+// The store owns its elements (it is not Clone, as two copies would free the same mapping and
+// elements), so it can move to another thread whenever they can.
+unsafe impl<T: Send> Send for BackingStore<T> {}
 
 impl<T> BackingStore<T> {
     pub fn new(len: usize) -> Result<BackingStore<T>, AllocationFailed> {
+        let size = BackingStore::<T>::size_of(len)?;
+        // the mapping is private so that it can be grown with mremap (the shmem object behind a
+        // shared anonymous mapping would keep its size, leaving the new pages unbacked)
+        BackingStore::map(len, size, mman::MapFlags::MAP_PRIVATE | mman::MapFlags::MAP_ANONYMOUS, -1, 0)
+    }
+
+    /// Same as new, but the elements are preferably placed in the memory of the NUMA node `node`,
+    /// for stores used by threads pinned to that node.
+    /// This is only a hint: the store is allocated anyway when the system has no NUMA support,
+    /// `node` doesn't exist or its memory is exhausted.
+    pub fn new_on_node(len: usize, node: i32) -> Result<BackingStore<T>, AllocationFailed> {
+        let store = BackingStore::new(len)?;
+        store.bind_to_node(node);
+        Ok(store)
+    }
+
+    // Size in bytes of len elements
+    fn size_of(len: usize) -> Result<usize, AllocationFailed> {
+        // a wrapped around size would give us a mapping way too small for len elements
+        len.checked_mul(mem::size_of::<T>()).ok_or(AllocationFailed {})
+    }
+
+    fn map(len: usize, size: usize, flags: mman::MapFlags, fd: RawFd, offset: libc::off_t) -> Result<BackingStore<T>, AllocationFailed> {
         let backing_store = unsafe {
             // Map into memory and let backing_store point to it
-            // TODO: handle alignment
-            match mman::mmap(0 as *mut libc::c_void, len*mem::size_of::<T>(), mman::ProtFlags::PROT_READ | mman::ProtFlags::PROT_WRITE, mman::MapFlags::MAP_SHARED | mman::MapFlags::MAP_ANONYMOUS, -1, 0) {
+            // mmap returns page-aligned addresses, which is enough for any sane T, but an
+            // over-aligned T may still ask for more than that
+            match mman::mmap(ptr::null_mut(), size, mman::ProtFlags::PROT_READ | mman::ProtFlags::PROT_WRITE, flags, fd, offset) {
                 Ok(x) => x as *mut T,
                 Err(_) => {
                     return Err(AllocationFailed {});
                 }
             }
         };
+        if !backing_store.is_aligned() {
+            unsafe {
+                let _ = mman::munmap(backing_store as *mut libc::c_void, size);
+            }
+            return Err(AllocationFailed {});
+        }
         Ok(BackingStore {
             len,
-            data: backing_store
+            data: backing_store,
+            file_backed: false,
+            #[cfg(debug_assertions)]
+            initialized: Arc::new(InitBitmap::new(len, false))
         })
     }
+}
+
+impl<T: Copy> BackingStore<T> {
+    /// Map the file at `path` (created if needed and truncated to the size of len elements), so
+    /// that the elements outlive the process or can be shared with other processes mapping the
+    /// same file.
+    /// Only Copy types are allowed, as pointers and owned resources make no sense across processes.
+    ///
+    /// # Safety
+    /// The elements are read from the file as they are: whatever bytes it holds (zeroes where it
+    /// was extended, what a previous run or another process wrote elsewhere) must make valid
+    /// values of T. In practice T must be plain old data, without references, bools, chars,
+    /// enums or any other type some bit patterns are invalid for.
+    pub unsafe fn new_file(path: &Path, len: usize) -> Result<BackingStore<T>, AllocationFailed> {
+        let size = BackingStore::<T>::size_of(len)?;
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).map_err(|_| AllocationFailed {})?;
+        file.set_len(size as u64).map_err(|_| AllocationFailed {})?;
+        // the mapping stays valid once the file is closed
+        BackingStore::new_fd(file.as_raw_fd(), 0, len)
+    }
 
-    // Beware of being within bounds, no checks will be done
-    pub fn get(&self, pos: usize) -> T {
+    /// Map len elements of the file (or shared memory object) `fd`, starting at `offset` bytes,
+    /// which must be a multiple of the page size.
+    /// The file must already be big enough to hold them, and the mapping stays valid once it is
+    /// closed.
+    ///
+    /// # Safety
+    /// Same as new_file: the bytes of the file must make valid values of T.
+    pub unsafe fn new_fd(fd: RawFd, offset: libc::off_t, len: usize) -> Result<BackingStore<T>, AllocationFailed> {
+        let size = BackingStore::<T>::size_of(len)?;
+        let mut store = BackingStore::map(len, size, mman::MapFlags::MAP_SHARED, fd, offset)?;
+        store.file_backed = true;
+        // the caller vouched for the content of the file, every slot holds a T
+        #[cfg(debug_assertions)]
+        {
+            store.initialized = Arc::new(InitBitmap::new(len, true));
+        }
+        Ok(store)
+    }
+}
+
+impl<T> BackingStore<T> {
+    // Address of the slot at pos
+    fn slot(&self, pos: usize) -> *mut T {
         let ptr = (self.data as usize + pos * mem::size_of::<T>()) as *mut T;
-        unsafe {
-            mem::transmute_copy(&*ptr)
+        debug_assert!(ptr.is_aligned(), "misaligned slot");
+        ptr
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_initialized(&self, pos: usize) {
+        assert!(self.initialized.is_set(pos), "slot {} was read before being set", pos);
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline(always)]
+    fn check_initialized(&self, _pos: usize) {}
+
+    #[cfg(debug_assertions)]
+    fn mark_initialized(&self, pos: usize, initialized: bool) {
+        self.initialized.mark(pos, initialized);
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline(always)]
+    fn mark_initialized(&self, _pos: usize, _initialized: bool) {}
+
+    /// Move the element at `pos` out of the store. No check is done, this is meant for the
+    /// audited hot paths of the message queue.
+    ///
+    /// # Safety
+    /// The slot must be within bounds and hold an element (set and not moved out since), and
+    /// must be considered uninitialized afterwards.
+    pub unsafe fn get(&self, pos: usize) -> T {
+        self.check_initialized(pos);
+        self.mark_initialized(pos, false);
+        ptr::read(self.slot(pos))
+    }
+
+    /// Same as get, but borrows the element instead of moving it out.
+    ///
+    /// # Safety
+    /// The slot must be within bounds and hold an element (set and not moved out since), and must
+    /// not be written to while the reference is alive.
+    pub unsafe fn get_ref(&self, pos: usize) -> &T {
+        self.check_initialized(pos);
+        &*self.slot(pos)
+    }
+
+    /// Same as get_ref, but the element can be modified in place.
+    ///
+    /// # Safety
+    /// On top of the requirements of get_ref, this must be the only reference to this element
+    /// while it is alive: the store can't check it, as it hands out references from `&self`.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut(&self, pos: usize) -> &mut T {
+        self.check_initialized(pos);
+        &mut *self.slot(pos)
+    }
+
+    /// Same as get_ref, for the `count` elements starting at `pos`.
+    ///
+    /// # Safety
+    /// The requirements of get_ref apply to every slot of the range.
+    pub unsafe fn get_slice(&self, pos: usize, count: usize) -> &[T] {
+        for pos in pos..pos+count {
+            self.check_initialized(pos);
+        }
+        slice::from_raw_parts(self.slot(pos), count)
+    }
+
+    /// Same as get_slice, but the elements can be modified in place.
+    ///
+    /// # Safety
+    /// The requirements of get_mut apply to every slot of the range.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_slice_mut(&self, pos: usize, count: usize) -> &mut [T] {
+        for pos in pos..pos+count {
+            self.check_initialized(pos);
+        }
+        slice::from_raw_parts_mut(self.slot(pos), count)
+    }
+
+    /// Drop the `count` elements starting at `pos` in place, leaving their slots uninitialized.
+    ///
+    /// # Safety
+    /// Every slot of the range must be within bounds and hold an element, which nobody else may
+    /// use anymore: it must not be borrowed, and must not be read or dropped again before being
+    /// set anew.
+    pub unsafe fn drop_slots(&self, pos: usize, count: usize) {
+        for pos in pos..pos+count {
+            self.check_initialized(pos);
+            self.mark_initialized(pos, false);
         }
+        ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.slot(pos), count));
     }
 
-    pub fn set(&self, pos: usize, val: T) {
+    /// Store `val` at `pos`, see try_set for the checked version.
+    /// The previous content of the slot, if any, is not dropped: it is expected to be
+    /// uninitialized (or already moved out with get).
+    ///
+    /// # Safety
+    /// The slot must be within bounds, and must not be borrowed.
+    pub unsafe fn set(&self, pos: usize, val: T) {
+        ptr::write(self.slot(pos), val);
+        self.mark_initialized(pos, true);
+    }
+
+    /// Move the `count` elements starting at `src` to `dst` (the ranges may overlap), leaving the
+    /// slots of `src` (outside of `dst`) uninitialized.
+    ///
+    /// # Safety
+    /// Both ranges must be within bounds, every slot of `src` must hold an element, and the slots
+    /// of `dst` outside of `src` must be uninitialized, as their content is overwritten without
+    /// being dropped. None of these slots may be borrowed.
+    pub unsafe fn move_slots(&self, src: usize, dst: usize, count: usize) {
+        for pos in src..src+count {
+            self.check_initialized(pos);
+            self.mark_initialized(pos, false);
+        }
+        ptr::copy(self.slot(src), self.slot(dst), count);
+        for pos in dst..dst+count {
+            self.mark_initialized(pos, true);
+        }
+    }
+
+    // Change the number of elements the store can hold, the elements may be moved in memory so no
+    // reference to them may survive this
+    // Only anonymous stores can be resized
+    #[cfg(target_os = "linux")]
+    pub fn resize(&mut self, new_len: usize) -> Result<(), AllocationFailed> {
+        if self.file_backed {
+            return Err(AllocationFailed {});
+        }
+        let old_size = BackingStore::<T>::size_of(self.len)?;
+        let new_size = BackingStore::<T>::size_of(new_len)?;
+        let data = unsafe {
+            libc::mremap(self.data as *mut libc::c_void, old_size, new_size, libc::MREMAP_MAYMOVE)
+        };
+        if data == libc::MAP_FAILED {
+            return Err(AllocationFailed {});
+        }
+        // mremap keeps the page alignment we checked in new
+        self.data = data as *mut T;
+        self.len = new_len;
+        #[cfg(debug_assertions)]
+        {
+            self.initialized = Arc::new(self.initialized.resized(new_len));
+        }
+        Ok(())
+    }
+
+    // Tell the kernel the elements will be accessed in order, so that it reads ahead more
+    // aggressively (only useful for big stores)
+    // This is only a hint, and does nothing where the kernel doesn't support it
+    pub fn advise_sequential(&self) {
+        self.advise(libc::POSIX_MADV_SEQUENTIAL);
+    }
+
+    // Tell the kernel the elements will be accessed soon, so that it can start bringing them in
+    pub fn advise_willneed(&self) {
+        self.advise(libc::POSIX_MADV_WILLNEED);
+    }
+
+    fn advise(&self, advice: libc::c_int) {
+        if let Ok(size) = BackingStore::<T>::size_of(self.len) {
+            unsafe {
+                // failing is harmless, nothing to do about it
+                let _ = libc::posix_madvise(self.data as *mut libc::c_void, size, advice);
+            }
+        }
+    }
+
+    // Ask the kernel to place the pages of the store on the NUMA node `node`, returning whether
+    // it agreed to
+    // Only the pages not touched yet are affected, so this is best done right after the mapping
+    #[cfg(target_os = "linux")]
+    pub fn bind_to_node(&self, node: i32) -> bool {
+        // the kernel takes a bitmask of the allowed nodes, 1024 of them ought to be enough
+        const MASK_WORDS: usize = 16;
+        const WORD_BITS: usize = mem::size_of::<libc::c_ulong>()*8;
+        let size = match BackingStore::<T>::size_of(self.len) {
+            Ok(size) if size > 0 => size,
+            _ => return false
+        };
+        if node < 0 || node as usize >= MASK_WORDS*WORD_BITS {
+            return false;
+        }
+        let mut mask: [libc::c_ulong; MASK_WORDS] = [0; MASK_WORDS];
+        mask[node as usize/WORD_BITS] = 1 << (node as usize%WORD_BITS);
+        // MPOL_PREFERRED rather than MPOL_BIND, so that we fall back to the other nodes instead
+        // of faulting when the node is out of memory
         unsafe {
-            *((self.data as usize + pos * mem::size_of::<T>()) as *mut T) = val;
+            libc::syscall(libc::SYS_mbind, self.data as *mut libc::c_void, size, libc::MPOL_PREFERRED,
+                mask.as_ptr(), MASK_WORDS*WORD_BITS, 0) == 0
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn bind_to_node(&self, _node: i32) -> bool {
+        false
+    }
+
+    /// Same as get, but fails instead of reading out of the store.
+    ///
+    /// # Safety
+    /// Only the bounds are checked: the slot must hold an element (set and not moved out since),
+    /// which must be considered uninitialized afterwards.
+    pub unsafe fn try_get(&self, pos: usize) -> Option<T> {
+        if pos < self.len {
+            Some(self.get(pos))
+        } else {
+            None
+        }
+    }
+
+    // Same as set, but fails instead of writing out of the store
+    pub fn try_set(&self, pos: usize, val: T) -> Result<(), OutOfBounds> {
+        if pos < self.len {
+            // whoever borrowed the slot promised not to let it be written to (see get_ref)
+            unsafe { self.set(pos, val) };
+            Ok(())
+        } else {
+            Err(OutOfBounds {})
         }
     }
 }
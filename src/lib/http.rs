@@ -1,8 +1,11 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
+use std::mem::ManuallyDrop;
 use std::str;
-use std::mem;
+use crate::lib::parser::{parse, Consumer, ConsumerToEnd, InvalidStateError, Limit, Number, ParserError, ParserEvaluator, ParserState, ReaderUntil, Take};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum HTTPVerb {
     GET,
     POST,
@@ -11,11 +14,14 @@ pub enum HTTPVerb {
     DELETE,
     OPTIONS,
     TRACE,
-    CONNECT
+    CONNECT,
+    /// Any other method, as sent by the client
+    Unknown(String)
 }
 
 impl HTTPVerb {
-    fn parse_from_utf8(verb: &[u8]) -> Option<Self> {
+    /// The known method spelled `verb`, if any (see `as_str` for the opposite).
+    pub fn parse_from_utf8(verb: &[u8]) -> Option<Self> {
         match verb {
             b"GET" => Some(HTTPVerb::GET),
             b"POST" => Some(HTTPVerb::POST),
@@ -28,66 +34,716 @@ impl HTTPVerb {
             _ => None
         }
     }
+
+    /// The method as sent on the wire
+    pub fn as_str(&self) -> &str {
+        match self {
+            HTTPVerb::GET => "GET",
+            HTTPVerb::POST => "POST",
+            HTTPVerb::PUT => "PUT",
+            HTTPVerb::HEAD => "HEAD",
+            HTTPVerb::DELETE => "DELETE",
+            HTTPVerb::OPTIONS => "OPTIONS",
+            HTTPVerb::TRACE => "TRACE",
+            HTTPVerb::CONNECT => "CONNECT",
+            HTTPVerb::Unknown(verb) => verb
+        }
+    }
+
+    /// Parse the method of a request line, keeping the unknown ones as long as they are valid
+    /// tokens (RFC 7230).
+    fn parse(verb: &[u8]) -> Result<Self, ParserError> {
+        if let Some(verb) = HTTPVerb::parse_from_utf8(verb) {
+            return Ok(verb);
+        }
+        let is_tchar = |c: &u8| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(c);
+        if verb.is_empty() || !verb.iter().all(is_tchar) {
+            return Err(ParserError::InvalidData);
+        }
+        // only ascii, can't fail
+        Ok(HTTPVerb::Unknown(String::from_utf8(verb.to_vec())?))
+    }
+}
+
+impl fmt::Display for HTTPVerb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    Http10,
+    Http11
+}
+
+impl HttpVersion {
+    /// Parse the version of a request line, only HTTP/1.x is supported.
+    /// Per RFC 7230, a higher minor version is handled as the highest one we know of.
+    fn parse(version: &[u8]) -> Result<Self, ParserError> {
+        match version {
+            b"HTTP/1.0" => Ok(HttpVersion::Http10),
+            [b'H', b'T', b'T', b'P', b'/', b'1', b'.', minor] if minor.is_ascii_digit() => Ok(HttpVersion::Http11),
+            _ => Err(ParserError::InvalidData)
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1"
+        }
+    }
+
+    /// Whether connections stay open after a query unless told otherwise (with a Connection
+    /// header)
+    pub fn keep_alive_by_default(&self) -> bool {
+        *self == HttpVersion::Http11
+    }
 }
 
-// yes, there are many allocations, deal with it ;)
+// yes, there are many allocations, deal with it ;) (or reuse the headers, see parse_into)
 #[derive(Debug, Clone)]
 pub struct HttpQuery<'a> {
     pub verb: HTTPVerb,
+    pub version: HttpVersion,
+    // the raw request target, see path and query for its parts
     pub url: &'a str,
+    pub path: &'a str,
+    // everything after the first '?' of the url, if any
+    pub query: Option<&'a str>,
     // the body remain an array of u8 because it can be binary data
-    pub body: &'a [u8],
-    pub headers: HashMap<&'a str, &'a str>
-}
-
-//impl<'a> HttpQuery<'a> {
-//    pub fn from_string(q: &'a [u8]) -> Result<Self, ParserError> {
-//        let mut parser = Parser {
-//            string: q,
-//            pos: 0
-//        };
-//        // ignore any CLRF before the Request-Line, per the specification (https://www.w3.org/Protocols/rfc2616/rfc2616-sec4.html)
-//        parser.advance_while_any(b"\r\n")?;
-//
-//        // match the http verb
-//        let verb = HTTPVerb::parse_from_utf8(parser.get_until(b" ")?).unwrap_or(HTTPVerb::GET);
-//
-//        // retrieve the queried url
-//        let url = unsafe { mem::transmute(str::from_utf8_unchecked(parser.get_until(b" ")?)) };
-//
-//        // check the request is well formed
-//        if parser.get_until(b"\r\n")? != b"HTTP/1.1" {
-//            return Err(ParserError::InvalidData);
-//        }
-//
-//        let mut headers = HashMap::new();
-//        loop {
-//            let header = parser.get_until(b"\r\n")?;
-//            if header.len() == 0 {
-//                break;
-//            }
-//
-//            let mut pos = 0;
-//            for i in 1..header.len()-1 {
-//                if header[i] == b':' {
-//                    pos = i;
-//                    break;
-//                }
-//            }
-//            if pos == 0 {
-//                return Err(ParserError::InvalidData);
-//            }
-//            // yes, this is awfully wrong, but it works ! Besides, we can do less allocations like that.
-//            unsafe {
-//                headers.insert(mem::transmute(str::from_utf8_unchecked(&header[..pos])), mem::transmute(str::from_utf8_unchecked(&header[pos+1..])));
-//            }
-//        }
-//
-//        Ok(HttpQuery {
-//            verb,
-//            url,
-//            headers,
-//            body: parser.get_until_eof()
-//        })
-//    }
-//}
+    // it is only copied when it has to be reassembled (chunked transfer encoding)
+    pub body: Cow<'a, [u8]>,
+    // in the order they were sent, see header and headers_all to look them up
+    pub headers: Vec<(&'a str, &'a str)>,
+    // what follows the body when its length is known (e.g. the next pipelined query)
+    pub trailing: &'a [u8]
+}
+
+/// Same as HttpQuery, but owns its data so that it can outlive the buffer the query was read
+/// from, and be sent to another thread.
+/// The trailing bytes are left out, they belong to the connection rather than to the query.
+#[derive(Debug, Clone)]
+pub struct OwnedHttpQuery {
+    pub verb: HTTPVerb,
+    pub version: HttpVersion,
+    pub url: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub body: Vec<u8>,
+    pub headers: Vec<(String, String)>
+}
+
+impl<'a> From<&HttpQuery<'a>> for OwnedHttpQuery {
+    fn from(query: &HttpQuery<'a>) -> Self {
+        OwnedHttpQuery {
+            verb: query.verb.clone(),
+            version: query.version,
+            url: query.url.to_owned(),
+            path: query.path.to_owned(),
+            query: query.query.map(str::to_owned),
+            body: query.body.to_vec(),
+            headers: query.headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        }
+    }
+}
+
+impl OwnedHttpQuery {
+    /// See HttpQuery::header
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header_values(&self.headers, name).next()
+    }
+
+    /// See HttpQuery::headers_all
+    pub fn headers_all(&self, name: &str) -> Vec<&str> {
+        header_values(&self.headers, name).collect()
+    }
+
+    /// See HttpQuery::headers_in_order
+    pub fn headers_in_order(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// See HttpQuery::query_params
+    pub fn query_params(&self) -> HashMap<String, String> {
+        parse_query_params(self.query.as_deref())
+    }
+
+    /// See HttpQuery::decoded_path
+    pub fn decoded_path(&self) -> Result<String, ParserError> {
+        Ok(String::from_utf8(percent_decode(&self.path, false)?)?)
+    }
+
+    /// See HttpQuery::body_str
+    pub fn body_str(&self) -> Result<&str, ParserError> {
+        Ok(std::str::from_utf8(&self.body)?)
+    }
+
+    /// See HttpQuery::form_params
+    pub fn form_params(&self) -> HashMap<String, String> {
+        parse_form_params(&self.headers, &self.body)
+    }
+}
+
+/// Values of the headers called `name`, ignoring case
+fn header_values<'h: 'n, 'n, S: AsRef<str>>(headers: &'h [(S, S)], name: &'n str) -> impl Iterator<Item = &'h str> + 'n {
+    headers.iter()
+        .filter(move |(key, _)| key.as_ref().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_ref())
+}
+
+/// Decode the %XX escapes of `s`, and turn '+' into spaces if `plus_as_space` is set.
+/// Fails on malformed escapes.
+fn percent_decode(s: &str, plus_as_space: bool) -> Result<Vec<u8>, ParserError> {
+    let bytes = s.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let escape = bytes.get(i+1..i+3).ok_or(ParserError::InvalidData)?;
+                let hex_value = |c: u8| (c as char).to_digit(16).ok_or(ParserError::InvalidData);
+                res.push((hex_value(escape[0])? << 4 | hex_value(escape[1])?) as u8);
+                i += 3;
+                continue;
+            },
+            b'+' if plus_as_space => res.push(b' '),
+            c => res.push(c)
+        }
+        i += 1;
+    }
+    Ok(res)
+}
+
+fn parse_query_params(query: Option<&str>) -> HashMap<String, String> {
+    // keep malformed parts as they are rather than dropping them
+    let decode = |s: &str| match percent_decode(s, true) {
+        Ok(x) => String::from_utf8_lossy(&x).into_owned(),
+        Err(_) => s.to_owned()
+    };
+    query.unwrap_or("")
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+/// Parameters of an application/x-www-form-urlencoded body, none for other types of bodies
+fn parse_form_params<S: AsRef<str>>(headers: &[(S, S)], body: &[u8]) -> HashMap<String, String> {
+    let is_form = header_values(headers, "Content-Type").next()
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/x-www-form-urlencoded"));
+    if !is_form {
+        return HashMap::new();
+    }
+    // the form should be ASCII, whatever else is in it ends up in the values as for query strings
+    parse_query_params(Some(&String::from_utf8_lossy(body)))
+}
+
+/// Media ranges of the Accept headers with their quality: `text/html;q=0.8, */*` gives
+/// [("text/html", 0.8), ("*/*", 1.0)].
+/// Ranges with an invalid quality are ignored.
+fn parse_accept<'a>(accept: &[&'a str]) -> Vec<(&'a str, f32)> {
+    accept.iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media = params.next().filter(|media| !media.is_empty())?;
+            let quality = match params.find_map(|param| param.strip_prefix("q=")) {
+                Some(q) => q.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))?,
+                None => 1.0
+            };
+            Some((media, quality))
+        })
+        .collect()
+}
+
+/// Quality the client gives to `mime` according to its media `ranges`, the most specific range
+/// matching it taking precedence: `text/html` over `text/*` over `*/*`.
+fn accept_quality(ranges: &[(&str, f32)], mime: &str) -> f32 {
+    let (kind, _) = mime.split_once('/').unwrap_or((mime, ""));
+    ranges.iter()
+        .filter_map(|(range, quality)| {
+            let specificity = if range.eq_ignore_ascii_case(mime) {
+                2
+            } else if range.strip_suffix("/*").is_some_and(|x| x.eq_ignore_ascii_case(kind)) {
+                1
+            } else if *range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            Some((specificity, *quality))
+        })
+        // the first range wins among the equally specific ones
+        .fold(None, |best: Option<(u8, f32)>, x| match best {
+            Some(best) if best.0 >= x.0 => Some(best),
+            _ => Some(x)
+        })
+        .map_or(0.0, |(_, quality)| quality)
+}
+
+/// Empty `headers` so that they can hold headers borrowed from another query
+fn reuse_headers<'b>(mut headers: Vec<(&str, &str)>) -> Vec<(&'b str, &'b str)> {
+    headers.clear();
+    let mut headers = ManuallyDrop::new(headers);
+    // there is no element left, and the types only differ by their lifetimes: the allocation
+    // fits the new vector as it is
+    unsafe { Vec::from_raw_parts(headers.as_mut_ptr().cast(), 0, headers.capacity()) }
+}
+
+/// Return everything up to `pattern`, and move past it
+fn read_field<'a>(q: &'a [u8], state: &mut ParserState, pattern: &[u8]) -> Result<&'a [u8], ParserError> {
+    let start = state.pos();
+    let field = ReaderUntil::consuming(pattern).evaluate(q, state)?;
+    if start + field.len() == state.pos() {
+        // we reached the end of the query without finding the pattern
+        return Err(ParserError::InvalidState(InvalidStateError::EOF));
+    }
+    Ok(field)
+}
+
+/// Same as read_field, but fails with `err` as soon as the field is longer than `max`, even when
+/// its end hasn't arrived yet.
+fn read_field_bounded<'a>(q: &'a [u8], state: &mut ParserState, pattern: &[u8], max: usize, err: ParserError) -> Result<&'a [u8], ParserError> {
+    let start = state.pos();
+    match read_field(q, state, pattern) {
+        Ok(field) if field.len() > max => Err(err),
+        // the end of the input may be the start of the pattern
+        Err(ParserError::InvalidState(InvalidStateError::EOF)) if q.len()-start > max.saturating_add(pattern.len()-1) => Err(err),
+        res => res
+    }
+}
+
+/// Return the next `len` bytes
+fn read_n<'a>(q: &'a [u8], state: &mut ParserState, len: usize) -> Result<&'a [u8], ParserError> {
+    Take::new(len).evaluate(q, state).map_err(|e| match e {
+        // the rest of the query hasn't arrived yet
+        ParserError::OutOfBoundsAccess => ParserError::InvalidState(InvalidStateError::EOF),
+        e => e
+    })
+}
+
+fn is_crlf(s: &[u8]) -> Result<usize, ParserError> {
+    Ok(match s.first() {
+        Some(b'\r') | Some(b'\n') => 1,
+        _ => 0
+    })
+}
+
+fn to_usize(n: u64) -> Result<usize, ParserError> {
+    std::convert::TryFrom::try_from(n).map_err(|_| ParserError::Overflow)
+}
+
+/// Reassemble a body sent with the chunked transfer encoding.
+/// The trailer fields, if any, are skipped.
+fn read_chunked(q: &[u8], state: &mut ParserState) -> Result<Vec<u8>, ParserError> {
+    let mut body = Vec::new();
+    loop {
+        let line = str::from_utf8(read_field(q, state, b"\r\n")?)?;
+        // ignore the chunk extensions
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = to_usize(parse(&Number::hex(), size.as_bytes())?)?;
+        if size == 0 {
+            break;
+        }
+        body.extend_from_slice(read_n(q, state, size)?);
+        if read_n(q, state, 2)? != b"\r\n" {
+            return Err(ParserError::InvalidData);
+        }
+    }
+    while !read_field(q, state, b"\r\n")?.is_empty() {}
+    Ok(body)
+}
+
+/// Bounds on the size of a query, so that a malicious client can't make us waste time and memory
+/// on it. Crossing one of them fails the parsing with LimitExceeded, telling which one it was.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    pub max_headers: usize,
+    /// Total size of the header lines
+    pub max_header_bytes: usize,
+    pub max_url_len: usize,
+    /// Size of the body as sent, i.e. including the chunk sizes and trailers of a chunked body
+    pub max_body_bytes: usize
+}
+
+impl ParseLimits {
+    pub fn unlimited() -> Self {
+        ParseLimits {
+            max_headers: usize::MAX,
+            max_header_bytes: usize::MAX,
+            max_url_len: usize::MAX,
+            max_body_bytes: usize::MAX
+        }
+    }
+}
+
+impl Default for ParseLimits {
+    /// Limits generous enough for any browser
+    fn default() -> Self {
+        ParseLimits {
+            max_headers: 100,
+            max_header_bytes: 16384,
+            max_url_len: 8192,
+            max_body_bytes: 1 << 20
+        }
+    }
+}
+
+impl<'a> HttpQuery<'a> {
+    /// Parse a query, without any limit on its size (see from_string_limited).
+    pub fn from_string(q: &'a [u8]) -> Result<Self, ParserError> {
+        HttpQuery::from_string_limited(q, ParseLimits::unlimited())
+    }
+
+    pub fn from_string_limited(q: &'a [u8], limits: ParseLimits) -> Result<Self, ParserError> {
+        HttpQuery::parse_query(q, limits, true, Vec::new())
+    }
+
+    /// Same as from_string, but stores the headers in `headers` (cleared beforehand) instead of
+    /// allocating a new vector, so that a server can reuse the one of its previous query.
+    /// See recycle_headers to take the vector back once done with the query.
+    pub fn parse_into(q: &'a [u8], headers: Vec<(&str, &str)>) -> Result<Self, ParserError> {
+        HttpQuery::parse_query(q, ParseLimits::unlimited(), true, reuse_headers(headers))
+    }
+
+    /// Drop the query, keeping the (emptied) vector of its headers for parse_into.
+    /// Unlike the `headers` field, it no longer borrows the buffer the query was read from.
+    pub fn recycle_headers(self) -> Vec<(&'static str, &'static str)> {
+        reuse_headers(self.headers)
+    }
+
+    /// Parse the queries pipelined in `buf`, e.g. on a keep-alive connection, until it is
+    /// exhausted.
+    /// As they follow each other, a query without a Content-Length or a chunked body has no body
+    /// here, instead of taking everything up to the end of the buffer.
+    /// A query cut short fails the whole parsing with EOF: read the rest before trying again.
+    pub fn parse_all(buf: &'a [u8]) -> Result<Vec<Self>, ParserError> {
+        let mut queries = Vec::new();
+        let mut rest = buf;
+        // the CRLFs a client may send between queries don't start a new one
+        while !rest.iter().all(|c| *c == b'\r' || *c == b'\n') {
+            let query = HttpQuery::parse_next(rest, ParseLimits::unlimited())?;
+            rest = query.trailing;
+            queries.push(query);
+        }
+        Ok(queries)
+    }
+
+    /// Parse the query at the start of `q`, the ones after it being left in `trailing`.
+    /// See parse_all.
+    pub(crate) fn parse_next(q: &'a [u8], limits: ParseLimits) -> Result<Self, ParserError> {
+        HttpQuery::parse_query(q, limits, false, Vec::new())
+    }
+
+    /// The body of a query with neither a Content-Length nor a chunked encoding takes the rest of
+    /// the input if `body_to_end` is set, and is empty otherwise.
+    fn parse_query(q: &'a [u8], limits: ParseLimits, body_to_end: bool, mut headers: Vec<(&'a str, &'a str)>) -> Result<Self, ParserError> {
+        let mut state = ParserState::new();
+        // ignore any CLRF before the Request-Line, per the specification (https://www.w3.org/Protocols/rfc2616/rfc2616-sec4.html)
+        Consumer::new(is_crlf).evaluate(q, &mut state)?;
+
+        // match the http verb
+        let verb = HTTPVerb::parse(read_field(q, &mut state, b" ")?)?;
+
+        // retrieve the queried url
+        let url = read_field_bounded(q, &mut state, b" ", limits.max_url_len, ParserError::LimitExceeded(Limit::Url))?;
+        let url = str::from_utf8(url)?;
+        let (path, query) = match url.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (url, None)
+        };
+
+        // check the request is well formed
+        let version = HttpVersion::parse(read_field(q, &mut state, b"\r\n")?)?;
+
+        let mut header_bytes = 0usize;
+        loop {
+            let left = limits.max_header_bytes.saturating_sub(header_bytes).saturating_sub(2);
+            let header = read_field_bounded(q, &mut state, b"\r\n", left, ParserError::LimitExceeded(Limit::Headers))?;
+            if header.is_empty() {
+                break;
+            }
+            header_bytes += header.len()+2;
+            if headers.len() == limits.max_headers {
+                return Err(ParserError::LimitExceeded(Limit::Headers));
+            }
+
+            let pos = match header.iter().position(|c| *c == b':') {
+                Some(pos) if pos > 0 => pos,
+                _ => return Err(ParserError::InvalidData)
+            };
+            let name = &header[..pos];
+            if name.iter().any(|c| *c == b' ' || *c == b'\t') {
+                return Err(ParserError::InvalidData);
+            }
+            // the value may be surrounded by optional whitespace
+            let value = str::from_utf8(&header[pos+1..])?.trim_matches(|c| c == ' ' || c == '\t');
+            // the header is a slice of the query, so no need to allocate anything
+            headers.push((str::from_utf8(name)?, value));
+        }
+
+        // the codings are applied in order, chunked coming last when the body is chunked: we
+        // can't tell where any other body ends
+        let codings = header_values(&headers, "Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty());
+        let chunked = match codings.last() {
+            None => false,
+            Some(coding) if coding.eq_ignore_ascii_case("chunked") => true,
+            Some(_) => return Err(ParserError::InvalidData)
+        };
+        // lengths that disagree would let a client smuggle a query in the body of another one, as
+        // we and a proxy in front of us may not pick the same (RFC 7230 §3.3.2)
+        let mut content_length = None;
+        for value in header_values(&headers, "Content-Length").flat_map(|value| value.split(',')) {
+            let len = to_usize(parse(&Number::new(), value.trim().as_bytes())?)?;
+            if content_length.is_some_and(|x| x != len) {
+                return Err(ParserError::InvalidData);
+            }
+            content_length = Some(len);
+        }
+        // a proxy in front of us may go by the other one (RFC 7230 §3.3.3)
+        if chunked && content_length.is_some() {
+            return Err(ParserError::InvalidData);
+        }
+        let (body, trailing) = match content_length {
+            _ if chunked => {
+                let start = state.pos();
+                let body = match read_chunked(q, &mut state) {
+                    Ok(_) if state.pos()-start > limits.max_body_bytes => Err(ParserError::LimitExceeded(Limit::Body)),
+                    Err(ParserError::InvalidState(InvalidStateError::EOF)) if q.len()-start > limits.max_body_bytes => Err(ParserError::LimitExceeded(Limit::Body)),
+                    res => res
+                }?;
+                (Cow::Owned(body), ConsumerToEnd::new().evaluate(q, &mut state)?)
+            },
+            // no need to wait for a body we won't accept
+            Some(len) if len > limits.max_body_bytes => return Err(ParserError::LimitExceeded(Limit::Body)),
+            Some(len) => (Cow::Borrowed(read_n(q, &mut state, len)?), ConsumerToEnd::new().evaluate(q, &mut state)?),
+            None if body_to_end => (Cow::Borrowed(ConsumerToEnd::new().evaluate(q, &mut state)?), &q[q.len()..]),
+            None => (Cow::Borrowed(&q[..0]), ConsumerToEnd::new().evaluate(q, &mut state)?)
+        };
+
+        Ok(HttpQuery {
+            verb,
+            version,
+            url,
+            path,
+            query,
+            headers,
+            body,
+            trailing
+        })
+    }
+
+    /// Value of the header called `name` (ignoring case), the first one if it was repeated.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header_values(&self.headers, name).next()
+    }
+
+    /// Values of every header called `name` (ignoring case), in the order they were sent.
+    pub fn headers_all(&self, name: &str) -> Vec<&str> {
+        header_values(&self.headers, name).collect()
+    }
+
+    /// Every header with its value, in the order they were sent (repeated ones included).
+    /// The lookups by name scan the headers as well: queries have few enough of them that an
+    /// index wouldn't be worth its allocation.
+    pub fn headers_in_order(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().copied()
+    }
+
+    /// Whether the client wants the connection to stay open after this query: the Connection
+    /// header has the last word, otherwise it depends on the version.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.headers_all("Connection");
+        let has = |option: &str| connection.iter()
+            .flat_map(|value| value.split(','))
+            .any(|x| x.trim().eq_ignore_ascii_case(option));
+        if has("close") {
+            false
+        } else {
+            has("keep-alive") || self.version.keep_alive_by_default()
+        }
+    }
+
+    /// Decode the parameters of the query string: `?k=v&k2=v2` gives {k: v, k2: v2}.
+    /// Keys and values are percent-decoded, and '+' stands for a space.
+    /// A key with no '=' gets an empty value, and when a key is repeated the last value wins.
+    /// Malformed escapes are kept undecoded.
+    pub fn query_params(&self) -> HashMap<String, String> {
+        parse_query_params(self.query)
+    }
+
+    /// Percent-decode the path: `/files/a%20b` gives `/files/a b`.
+    /// Malformed escapes are rejected with InvalidData, unlike in query_params, as the path
+    /// usually ends up selecting a resource. The raw url is left untouched.
+    pub fn decoded_path(&self) -> Result<String, ParserError> {
+        Ok(String::from_utf8(percent_decode(self.path, false)?)?)
+    }
+
+    /// The body as text, failing with UTFError if it isn't valid UTF-8.
+    /// The charset of the Content-Type is not looked at.
+    pub fn body_str(&self) -> Result<&str, ParserError> {
+        Ok(std::str::from_utf8(&self.body)?)
+    }
+
+    /// Decode the body of a form, sent with the application/x-www-form-urlencoded Content-Type,
+    /// like query_params decodes the query string.
+    /// Other bodies (or a missing Content-Type) give no parameters.
+    pub fn form_params(&self) -> HashMap<String, String> {
+        parse_form_params(&self.headers, &self.body)
+    }
+
+    /// Whether the client accepts responses of type `mime`, according to its Accept headers.
+    /// Everything is accepted without an Accept header.
+    pub fn accepts(&self, mime: &str) -> bool {
+        let accept = self.headers_all("Accept");
+        accept.is_empty() || accept_quality(&parse_accept(&accept), mime) > 0.0
+    }
+
+    /// Content negotiation: the type of `options` the client prefers (the first one in case of a
+    /// tie), or None if it accepts none of them.
+    pub fn preferred<'o>(&self, options: &[&'o str]) -> Option<&'o str> {
+        let accept = self.headers_all("Accept");
+        if accept.is_empty() {
+            return options.first().copied();
+        }
+        let ranges = parse_accept(&accept);
+        options.iter()
+            .map(|option| (*option, accept_quality(&ranges, option)))
+            .filter(|(_, quality)| *quality > 0.0)
+            // max_by would return the last of the best options
+            .fold(None, |best: Option<(&str, f32)>, x| match best {
+                Some(best) if best.1 >= x.1 => Some(best),
+                _ => Some(x)
+            })
+            .map(|(option, _)| option)
+    }
+
+    /// Serialize the query back, e.g. to forward it.
+    /// The headers are kept in order, but the framing of the body is redone: it is always sent
+    /// whole with a Content-Length, as it was already decoded if it was chunked.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(self.url.len()+self.body.len()+64);
+        res.extend_from_slice(format!("{} {} {}\r\n", self.verb.as_str(), self.url, self.version.as_str()).as_bytes());
+        let framing = |name: &str| name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding");
+        for (name, value) in self.headers.iter().filter(|(name, _)| !framing(name)) {
+            res.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if !self.body.is_empty() {
+            res.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        res.extend_from_slice(b"\r\n");
+        res.extend_from_slice(&self.body);
+        res
+    }
+
+    /// Same as from_string, but copies everything out of `q`.
+    pub fn from_string_owned(q: &[u8]) -> Result<OwnedHttpQuery, ParserError> {
+        HttpQuery::from_string(q).map(|query| OwnedHttpQuery::from(&query))
+    }
+}
+
+/// A response to send back to the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    // Content-Length is computed from the body when serializing, no need to add it
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body
+        }
+    }
+
+    pub fn ok(body: Vec<u8>) -> Self {
+        HttpResponse::new(200, body)
+    }
+
+    pub fn bad_request() -> Self {
+        HttpResponse::new(400, b"Bad Request".to_vec())
+    }
+
+    pub fn not_found() -> Self {
+        HttpResponse::new(404, b"Not Found".to_vec())
+    }
+
+    pub fn internal_error() -> Self {
+        HttpResponse::new(500, b"Internal Server Error".to_vec())
+    }
+
+    /// Response to a query rejected because it crossed `limit`
+    pub fn limit_exceeded(limit: Limit) -> Self {
+        let status = match limit {
+            Limit::Url => 414,
+            Limit::Headers => 431,
+            Limit::Body => 413
+        };
+        HttpResponse::new(status, reason_phrase(status).as_bytes().to_vec())
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Serialize the response as HTTP/1.1.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(64+self.body.len());
+        res.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status)).as_bytes());
+        for (name, value) in self.headers.iter().filter(|(name, _)| !name.eq_ignore_ascii_case("Content-Length")) {
+            res.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        res.extend_from_slice(format!("Content-Length: {}\r\n\r\n", self.body.len()).as_bytes());
+        res.extend_from_slice(&self.body);
+        res
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        // the reason phrase is purely informative
+        _ => ""
+    }
+}
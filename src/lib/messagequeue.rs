@@ -1,7 +1,14 @@
+use nix::fcntl::OFlag;
+use nix::sys::mman;
+use nix::sys::stat::{self, Mode};
+use nix::unistd;
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{io, thread};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::{hint, io, iter, mem, ptr, thread};
+use std::time::{Duration, Instant};
 use crate::lib::backingstore::BackingStore;
 
 /// The whole point of this struct is to be able to share it inside an Arc to prevent the sender
@@ -9,22 +16,185 @@ use crate::lib::backingstore::BackingStore;
 /// be dragons !)
 #[derive(Debug)]
 pub(crate) struct MessageQueueInternal<T> {
-    pub len: usize,
+    /// Storage of the messages, only replaced when growing the queue
+    ring: UnsafeCell<Ring<T>>,
+    /// Next slot to be claimed by a sender, only used by multi-producer queues.
+    /// It is ahead of write_ptr while some senders haven't finished writing their element.
+    reserve_ptr: AtomicUsize,
+    /// Positions of the writer and the reader. They only ever grow, the slot of position p being
+    /// p % len, so that a reader holding a stale position can't mistake it for the current one
+    /// once the ring has wrapped around.
+    write_ptr: Shareable<AtomicUsize>,
+    read_ptr: Shareable<AtomicUsize>,
+    multi_producer: bool,
+    /// Set for queues created with `new_lossy`: the sender may then drop the oldest message to
+    /// make room, so the reader has to claim each message with a CAS on read_ptr
+    lossy: bool,
+    /// Set for queues created with `new_spmc`: the readers race for each message, so they have to
+    /// claim it with a CAS on read_ptr too
+    multi_consumer: bool,
+    senders: Shareable<AtomicUsize>,
+    readers: Shareable<AtomicUsize>,
+    /// Set when the last reader is dropped, so that senders stop filling a queue nobody reads
+    readers_gone: Shareable<AtomicBool>,
+    /// Bumped every time the queue changes in a way a blocked reader may care about (new messages,
+    /// last sender leaving). Readers sleep on this word with a futex.
+    events: Shareable<AtomicU32>,
+    /// Number of readers sleeping on `events`, so that senders only do a syscall when needed
+    sleepers: Shareable<AtomicUsize>,
+    /// Only allocated for queues created with `new_with_stats`
+    counters: Option<QueueCounters>,
+    /// Header holding the shared fields of a queue shared between processes
+    shared: Option<SharedMapping>
+}
+
+/// A field of the queue, which lives in the queue itself unless the queue is shared between
+/// processes, in which case it lives in the header of the shared memory object.
+#[derive(Debug)]
+enum Shareable<A> {
+    Local(A),
+    Shared(*const A)
+}
+
+impl<A> Deref for Shareable<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        match self {
+            Shareable::Local(val) => val,
+            // the header outlives the fields pointing to it, see MessageQueueInternal::shared
+            Shareable::Shared(ptr) => unsafe { &**ptr }
+        }
+    }
+}
+
+/// Beginning of the shared memory object of a queue shared between processes, the messages
+/// follow on the next page.
+#[repr(C)]
+struct SharedHeader {
+    /// Set to SHARED_MAGIC once the header is initialized
+    magic: AtomicU64,
+    /// Number of slots in the ring
+    len: usize,
+    /// Size of the messages, so that a process expecting another type is (sometimes) caught
+    message_size: usize,
     write_ptr: AtomicUsize,
     read_ptr: AtomicUsize,
-    backing_store: BackingStore<T>
+    senders: AtomicUsize,
+    readers: AtomicUsize,
+    readers_gone: AtomicBool,
+    events: AtomicU32,
+    sleepers: AtomicUsize
+}
+
+const SHARED_MAGIC: u64 = 0x7765_6273_6572_7631;
+
+/// Mapping of the header of a shared queue
+#[derive(Debug)]
+struct SharedMapping {
+    header: *mut SharedHeader,
+    /// Name of the shared memory object, only set in the process that created it, which removes
+    /// it when the queue is dropped
+    owned_name: Option<String>
+}
+
+impl SharedMapping {
+    fn new(fd: RawFd, owned_name: Option<String>) -> Result<SharedMapping, MessageQueueError> {
+        let header = unsafe {
+            mman::mmap(ptr::null_mut(), mem::size_of::<SharedHeader>(), mman::ProtFlags::PROT_READ | mman::ProtFlags::PROT_WRITE,
+                mman::MapFlags::MAP_SHARED, fd, 0)?
+        };
+        Ok(SharedMapping {
+            header: header as *mut SharedHeader,
+            owned_name
+        })
+    }
+
+    fn header(&self) -> &SharedHeader {
+        unsafe { &*self.header }
+    }
+
+    // Offset of the messages in the shared memory object, which has to be page-aligned
+    fn data_offset() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+}
+
+impl Drop for SharedMapping {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = mman::munmap(self.header as *mut libc::c_void, mem::size_of::<SharedHeader>());
+        }
+        if let Some(ref name) = self.owned_name {
+            // the processes still attached keep their mappings
+            let _ = mman::shm_unlink(name.as_str());
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Ring<T> {
+    /// Number of slots in the ring, one more than the number of elements the queue can hold
+    len: usize,
+    backing_store: BackingStore<T>,
+    /// For the queues whose readers claim their messages (which only have a single sender): the
+    /// position each slot can next be written at. A reader moves read_ptr forward before moving
+    /// the message out, so the sender has to wait for it to release the slot.
+    stamps: Vec<AtomicUsize>
+}
+
+impl<T> Ring<T> {
+    fn new(len: usize, backing_store: BackingStore<T>, claimed: bool) -> Ring<T> {
+        let mut ring = Ring {
+            len,
+            backing_store,
+            stamps: Vec::new()
+        };
+        if claimed {
+            ring.reset_stamps(0);
+        }
+        ring
+    }
+
+    /// Slot holding the message at position `pos`
+    fn slot(&self, pos: usize) -> usize {
+        pos % self.len
+    }
+
+    /// Mark every slot as free for the first position at or after `write_pos` that maps to it.
+    fn reset_stamps(&mut self, write_pos: usize) {
+        let len = self.len;
+        self.stamps = (0..len).map(|slot| AtomicUsize::new(write_pos + (slot+len-write_pos%len)%len)).collect();
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueCounters {
+    peak_occupancy: AtomicUsize,
+    total_sent: AtomicUsize,
+    total_read: AtomicUsize
+}
+
+/// A snapshot of the statistics of a queue created with `MessageQueueSender::new_with_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueStats {
+    /// Highest number of messages the queue held at once
+    pub peak_occupancy: usize,
+    pub total_sent: usize,
+    pub total_read: usize
 }
 
 // this better work !
-unsafe impl<T> Send for MessageQueueInternal<T> { }
-unsafe impl<T> Sync for MessageQueueInternal<T> { }
+// The messages are moved from the senders' threads to the readers' ones, hence the bounds
+unsafe impl<T: Send> Send for MessageQueueInternal<T> { }
+unsafe impl<T: Send> Sync for MessageQueueInternal<T> { }
 
 #[derive(Debug)]
 pub struct MessageQueueSender<T> {
     internal: Arc<MessageQueueInternal<T>>
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct MessageQueueReader<T> {
     internal: Arc<MessageQueueInternal<T>>
 }
@@ -35,6 +205,12 @@ pub enum MessageQueueError {
     MemoryAllocationFailed,
     MessageQueueFull,
     MessageQueueEmpty,
+    /// Every reader of the queue has been dropped
+    Disconnected,
+    /// No message arrived before the deadline
+    TimedOut,
+    /// The shared memory object doesn't hold a queue of messages of this size
+    IncompatibleQueue,
     NixError(nix::Error)
 }
 
@@ -52,20 +228,173 @@ impl From<crate::lib::backingstore::AllocationFailed> for MessageQueueError {
 
 impl From<MessageQueueError> for io::Error {
     fn from(_: MessageQueueError) -> Self {
-        io::Error::new(io::ErrorKind::Other, "MessageQueueError")
+        io::Error::other("MessageQueueError")
     }
 }
 
 impl<T> MessageQueueInternal<T> {
+    fn ring(&self) -> &Ring<T> {
+        // only MessageQueueSender::grow ever changes it, and its caller guarantees that nobody else
+        // is looking
+        unsafe { &*self.ring.get() }
+    }
+
     /// Returns the distance between the reader and the writer on the data ring
     /// aka. the number of entries available to read
     pub fn dist(&self) -> usize {
         let writer_pos = self.write_ptr.load(Ordering::Acquire);
-        let reader_pos = self.read_ptr.load(Ordering::Acquire);
-        if writer_pos < reader_pos {
-            self.len+writer_pos-reader_pos
+        // the reader may have caught up with writes made after we looked at the writer
+        writer_pos.saturating_sub(self.read_ptr.load(Ordering::Acquire))
+    }
+
+    fn stats(&self) -> QueueStats {
+        match self.counters {
+            Some(ref counters) => QueueStats {
+                peak_occupancy: counters.peak_occupancy.load(Ordering::Relaxed),
+                total_sent: counters.total_sent.load(Ordering::Relaxed),
+                total_read: counters.total_read.load(Ordering::Relaxed)
+            },
+            None => QueueStats::default()
+        }
+    }
+
+    fn count_sent(&self, count: usize) {
+        if let Some(ref counters) = self.counters {
+            counters.total_sent.fetch_add(count, Ordering::Relaxed);
+            counters.peak_occupancy.fetch_max(self.dist(), Ordering::Relaxed);
+        }
+    }
+
+    fn count_read(&self, count: usize) {
+        if let Some(ref counters) = self.counters {
+            counters.total_read.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the readers have to claim each message with a CAS (see `read_claimed`)
+    fn claims_reads(&self) -> bool {
+        self.lossy || self.multi_consumer
+    }
+
+    /// Take the oldest message of a queue whose readers claim their messages, racing with the
+    /// other readers (or the sender of a lossy queue).
+    fn claim(&self) -> Option<T> {
+        let ring = self.ring();
+        let mut rptr = self.read_ptr.load(Ordering::Acquire);
+        loop {
+            if rptr >= self.write_ptr.load(Ordering::Acquire) {
+                return None;
+            }
+            match self.read_ptr.compare_exchange_weak(rptr, rptr+1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(x) => rptr = x
+            }
+        }
+        // the message is ours alone now, and the sender won't reuse its slot before we release it
+        let slot = ring.slot(rptr);
+        let val = unsafe { ring.backing_store.get(slot) };
+        ring.stamps[slot].store(rptr+ring.len, Ordering::Release);
+        Some(val)
+    }
+
+    /// Account for a new reader, checking that the queue can have several of them.
+    fn add_reader(&self) {
+        if self.multi_consumer {
+            self.readers.fetch_add(1, Ordering::AcqRel);
         } else {
-            writer_pos - reader_pos
+            let added = self.readers.compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire);
+            assert!(added.is_ok(), "only multi-consumer queues can have several readers");
+        }
+    }
+
+    /// Wake up the readers blocked in `blocking_read`, if any.
+    fn notify(&self) {
+        self.events.fetch_add(1, Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            futex_wake(&self.events, self.shared.is_some());
+        }
+    }
+}
+
+impl<T: Copy + 'static> MessageQueueInternal<T> {
+    /// Set up a queue of len slots in the shared memory object `fd`, which we just created as
+    /// `name`.
+    ///
+    /// # Safety
+    /// See `MessageQueueSender::new_shared`.
+    unsafe fn create_shared(fd: RawFd, name: &str, len: usize) -> Result<MessageQueueInternal<T>, MessageQueueError> {
+        let data_offset = SharedMapping::data_offset();
+        let size = len.checked_mul(mem::size_of::<T>()).and_then(|size| size.checked_add(data_offset))
+            .ok_or(MessageQueueError::MemoryAllocationFailed)?;
+        unistd::ftruncate(fd, size as libc::off_t)?;
+        let mapping = SharedMapping::new(fd, Some(name.to_string()))?;
+        // nobody uses the header before the magic is set, and ftruncate zeroed the rest of it
+        (*mapping.header).len = len;
+        (*mapping.header).message_size = mem::size_of::<T>();
+        mapping.header().senders.store(1, Ordering::Relaxed);
+        mapping.header().magic.store(SHARED_MAGIC, Ordering::Release);
+        let backing_store = BackingStore::new_fd(fd, data_offset as libc::off_t, len)?;
+        Ok(MessageQueueInternal::with_mapping(mapping, backing_store, len))
+    }
+
+    /// Map the queue set up by another process in the shared memory object `fd`.
+    ///
+    /// # Safety
+    /// See `MessageQueueSender::new_shared`.
+    unsafe fn attach_shared(fd: RawFd) -> Result<MessageQueueInternal<T>, MessageQueueError> {
+        let data_offset = SharedMapping::data_offset();
+        let size = stat::fstat(fd)?.st_size as usize;
+        if size < data_offset {
+            return Err(MessageQueueError::IncompatibleQueue);
+        }
+        let mapping = SharedMapping::new(fd, None)?;
+        let header = mapping.header();
+        if header.magic.load(Ordering::Acquire) != SHARED_MAGIC || header.message_size != mem::size_of::<T>() {
+            return Err(MessageQueueError::IncompatibleQueue);
+        }
+        // a bogus header must not make us map more than the object holds
+        let len = header.len;
+        let needed = len.checked_mul(mem::size_of::<T>()).and_then(|needed| needed.checked_add(data_offset));
+        if len == 0 || needed.is_none_or(|needed| needed > size) {
+            return Err(MessageQueueError::IncompatibleQueue);
+        }
+        let backing_store = BackingStore::new_fd(fd, data_offset as libc::off_t, len)?;
+        Ok(MessageQueueInternal::with_mapping(mapping, backing_store, len))
+    }
+
+    fn with_mapping(mapping: SharedMapping, backing_store: BackingStore<T>, len: usize) -> MessageQueueInternal<T> {
+        let header = mapping.header();
+        MessageQueueInternal {
+            ring: UnsafeCell::new(Ring::new(len, backing_store, false)),
+            reserve_ptr: AtomicUsize::new(0),
+            write_ptr: Shareable::Shared(&header.write_ptr),
+            read_ptr: Shareable::Shared(&header.read_ptr),
+            multi_producer: false,
+            lossy: false,
+            multi_consumer: false,
+            senders: Shareable::Shared(&header.senders),
+            readers: Shareable::Shared(&header.readers),
+            readers_gone: Shareable::Shared(&header.readers_gone),
+            events: Shareable::Shared(&header.events),
+            sleepers: Shareable::Shared(&header.sleepers),
+            counters: None,
+            shared: Some(mapping)
+        }
+    }
+}
+
+impl<T> Drop for MessageQueueInternal<T> {
+    fn drop(&mut self) {
+        // the messages of a shared queue may still be read by another process (and they don't
+        // need dropping anyway)
+        if self.shared.is_some() {
+            return;
+        }
+        // drop the messages that were never read, the backing store only frees the memory
+        let write_pos = self.write_ptr.load(Ordering::Acquire);
+        let ring = self.ring();
+        for pos in self.read_ptr.load(Ordering::Acquire)..write_pos {
+            drop(unsafe { ring.backing_store.get(ring.slot(pos)) });
         }
     }
 }
@@ -74,18 +403,77 @@ impl<T> MessageQueueInternal<T> {
 /// This create a sender object from which you can then create readers.
 impl<T: Sized> MessageQueueSender<T> {
     /// Create a new MessageQueueSender object, by specifying the number of elements 
-    /// it must be able to hold.
-    /// The size is thus fixed at creation and cannot be changed at runtime.
+    /// it must be able to hold (at least one).
+    /// The size is thus fixed at creation, and can only be changed afterwards with `grow`.
     pub fn new(num_elements: usize) -> Result<MessageQueueSender<T>, MessageQueueError> {
-        if num_elements < 2 {
+        MessageQueueSender::with_mode(num_elements, false, false, false, false)
+    }
+
+    /// Same as `new`, but the queue also keeps track of its statistics, see `stats`.
+    pub fn new_with_stats(num_elements: usize) -> Result<MessageQueueSender<T>, MessageQueueError> {
+        MessageQueueSender::with_mode(num_elements, false, false, false, true)
+    }
+
+    /// Create a new multi-producer queue: the sender can then be cloned to feed the queue from
+    /// several threads at once.
+    /// Sending is a bit more expensive than with a single-producer queue.
+    pub fn new_mpsc(num_elements: usize) -> Result<MessageQueueSender<T>, MessageQueueError> {
+        MessageQueueSender::with_mode(num_elements, true, false, false, false)
+    }
+
+    /// Create a queue with several readers taking messages concurrently, each message going to a
+    /// single one of them: clone the reader to get more of them, e.g. to spread work across
+    /// threads.
+    /// The readers claim every message with an atomic operation, and can only move messages out
+    /// of the queue (`read` and its variants): `peek`, `peek_mut` and `read_window` panic, as
+    /// another reader may take the message they would borrow.
+    pub fn new_spmc(num_elements: usize) -> Result<MessageQueueSender<T>, MessageQueueError> {
+        MessageQueueSender::with_mode(num_elements, false, true, false, false)
+    }
+
+    /// Create a lossy queue, for streams where the latest messages matter most (e.g. metrics):
+    /// `send_or_overwrite` then drops the oldest message instead of failing when the queue is
+    /// full, so the queue holds the newest ones.
+    /// The reader of such a queue can only move messages out of it (`read` and its variants):
+    /// `peek`, `peek_mut` and `read_window` panic, as the sender may drop the message they would
+    /// borrow.
+    pub fn new_lossy(num_elements: usize) -> Result<MessageQueueSender<T>, MessageQueueError> {
+        MessageQueueSender::with_mode(num_elements, false, false, true, false)
+    }
+
+    /// Same as `new`, but the messages are preferably stored in the memory of the NUMA node
+    /// `node`, for queues between threads pinned to that node.
+    /// This is only a hint, see `BackingStore::new_on_node`: the queue is created anyway when the
+    /// system has no NUMA support or the node doesn't exist.
+    pub fn new_on_node(num_elements: usize, node: i32) -> Result<MessageQueueSender<T>, MessageQueueError> {
+        let sender = MessageQueueSender::with_mode(num_elements, false, false, false, false)?;
+        // nobody else sees the queue yet, so none of its pages has been touched
+        sender.internal.ring().backing_store.bind_to_node(node);
+        Ok(sender)
+    }
+
+    fn with_mode(num_elements: usize, multi_producer: bool, multi_consumer: bool, lossy: bool, stats: bool) -> Result<MessageQueueSender<T>, MessageQueueError> {
+        if num_elements == 0 {
             return Err(MessageQueueError::UnvalidSize);
         }
+        // one slot is always left empty to tell a full ring from an empty one
+        let len = num_elements.checked_add(1).ok_or(MessageQueueError::UnvalidSize)?;
 
         let internal = MessageQueueInternal {
-            len: num_elements,
-            write_ptr: AtomicUsize::new(0),
-            read_ptr: AtomicUsize::new(0),
-            backing_store: BackingStore::new(num_elements)?
+            ring: UnsafeCell::new(Ring::new(len, BackingStore::new(len)?, multi_consumer || lossy)),
+            reserve_ptr: AtomicUsize::new(0),
+            write_ptr: Shareable::Local(AtomicUsize::new(0)),
+            read_ptr: Shareable::Local(AtomicUsize::new(0)),
+            multi_producer,
+            lossy,
+            multi_consumer,
+            senders: Shareable::Local(AtomicUsize::new(1)),
+            readers: Shareable::Local(AtomicUsize::new(0)),
+            readers_gone: Shareable::Local(AtomicBool::new(false)),
+            events: Shareable::Local(AtomicU32::new(0)),
+            sleepers: Shareable::Local(AtomicUsize::new(0)),
+            counters: if stats { Some(QueueCounters::default()) } else { None },
+            shared: None
         };
 
         Ok(MessageQueueSender {
@@ -93,82 +481,672 @@ impl<T: Sized> MessageQueueSender<T> {
         })
     }
 
-    /// Send a message to the queue
+    /// Send a message to the queue.
+    /// Fails with `Disconnected` once all the readers of the queue have been dropped.
     pub fn send(&mut self, val: T) -> Result<(), MessageQueueError> {
-        if self.internal.dist() == self.internal.len-1 {
-            return Err(MessageQueueError::MessageQueueFull);
+        if self.is_disconnected() {
+            return Err(MessageQueueError::Disconnected);
+        }
+        self.push(val).map_err(|_| MessageQueueError::MessageQueueFull)
+    }
+
+    /// Send a message to the queue, waiting for a slot to be freed if the queue is full.
+    /// This uses the same backing off algorithm as `MessageQueueReader::blocking_read`, and
+    /// gives up with `Disconnected` if all the readers are dropped in the meantime.
+    pub fn blocking_send(&mut self, val: T) -> Result<(), MessageQueueError> {
+        let mut val = Some(val);
+        backoff(|| {
+            if self.is_disconnected() {
+                return Some(Err(MessageQueueError::Disconnected));
+            }
+            match self.push(val.take().unwrap()) {
+                Ok(()) => Some(Ok(())),
+                Err(x) => {
+                    val = Some(x);
+                    None
+                }
+            }
+        })
+    }
+
+    /// Returns true when every reader of the queue has been dropped.
+    pub fn is_disconnected(&self) -> bool {
+        self.internal.readers_gone.load(Ordering::Acquire)
+    }
+
+    /// Maximum number of messages the queue can hold.
+    pub fn capacity(&self) -> usize {
+        self.internal.ring().len-1
+    }
+
+    /// Returns true when the queue can't take any more messages until some are read.
+    /// This is only a snapshot: readers and other senders may change it right away.
+    pub fn is_full(&self) -> bool {
+        self.internal.dist() == self.internal.ring().len-1
+    }
+
+    /// Returns true when no message is waiting to be read.
+    pub fn is_empty(&self) -> bool {
+        self.internal.dist() == 0
+    }
+
+    /// Send as many messages from `vals` as the queue can hold, and return how many were sent
+    /// (zero if the queue is full).
+    /// Unlike repeated calls to `send`, the readers are notified only once for the whole batch.
+    pub fn send_slice(&mut self, vals: &[T]) -> Result<usize, MessageQueueError> where T: Clone {
+        if self.is_disconnected() {
+            return Err(MessageQueueError::Disconnected);
+        }
+
+        let (wptr, count) = self.reserve(vals.len());
+        if count == 0 {
+            return Ok(0);
+        }
+        let ring = self.internal.ring();
+        // the readers don't touch these slots before they are published
+        for (i, val) in vals[..count].iter().enumerate() {
+            unsafe { ring.backing_store.set(ring.slot(wptr+i), val.clone()) };
+        }
+        self.publish(wptr, count);
+
+        Ok(count)
+    }
+
+    /// Append `val` to the queue, handing it back if the queue is full.
+    fn push(&mut self, val: T) -> Result<(), T> {
+        let (wptr, count) = self.reserve(1);
+        if count == 0 {
+            return Err(val);
+        }
+
+        let ring = self.internal.ring();
+        // the readers don't touch this slot before it is published
+        unsafe { ring.backing_store.set(ring.slot(wptr), val) };
+        self.publish(wptr, 1);
+
+        Ok(())
+    }
+
+    /// Send a message to a queue created with `new_lossy`, dropping the oldest message of the queue
+    /// if it is full.
+    /// Unlike `send`, this still works when every reader is gone: the messages are simply dropped.
+    ///
+    /// # Panics
+    /// Panics if the queue isn't lossy.
+    pub fn send_or_overwrite(&mut self, mut val: T) {
+        assert!(self.internal.lossy, "only lossy queues can overwrite their messages");
+        loop {
+            val = match self.push(val) {
+                Ok(()) => return,
+                Err(val) => val
+            };
+            if self.is_full() {
+                // drop the oldest message, unless a reader takes it first
+                drop(self.internal.claim());
+            } else {
+                // a reader is still moving the previous message of our slot out
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// Claim up to `max` consecutive slots to write to, returning the first one and how many were
+    /// claimed (zero if the queue is full).
+    /// With a multi-producer queue, this moves reserve_ptr forward so that other senders use the
+    /// following slots.
+    fn reserve(&self, max: usize) -> (usize, usize) {
+        let internal = &self.internal;
+        let len = internal.ring().len;
+        if !internal.multi_producer {
+            let wptr = internal.write_ptr.load(Ordering::Relaxed);
+            let count = max.min(len-1-internal.dist());
+            if !internal.claims_reads() {
+                return (wptr, count);
+            }
+            // a slot is only free once the reader that claimed its previous message released it
+            let ring = internal.ring();
+            let free = (0..count).take_while(|i| ring.stamps[ring.slot(wptr+i)].load(Ordering::Acquire) == wptr+i).count();
+            return (wptr, free);
+        }
+
+        let mut wptr = internal.reserve_ptr.load(Ordering::Acquire);
+        loop {
+            let rptr = internal.read_ptr.load(Ordering::Acquire);
+            let count = max.min(len-1-wptr.saturating_sub(rptr));
+            if count == 0 {
+                return (wptr, 0);
+            }
+            match internal.reserve_ptr.compare_exchange_weak(wptr, wptr+count, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return (wptr, count),
+                Err(x) => wptr = x
+            }
         }
+    }
 
-        let wptr = self.internal.write_ptr.load(Ordering::Relaxed);
-        self.internal.backing_store.set(wptr, val);
+    /// Hand the `count` slots written from `start` over to the readers.
+    /// With a multi-producer queue, this waits for every slot claimed before ours to be published.
+    fn publish(&self, start: usize, count: usize) {
+        let internal = &self.internal;
+        let next = start+count;
+        if !internal.multi_producer {
+            internal.write_ptr.store(next, Ordering::Release);
+        } else {
+            while internal.write_ptr.compare_exchange_weak(start, next, Ordering::Release, Ordering::Relaxed).is_err() {
+                hint::spin_loop();
+            }
+        }
+        internal.count_sent(count);
+        internal.notify();
+    }
 
-        self.internal.write_ptr.store((wptr+1)%self.internal.len, Ordering::Release);
+    /// Grow the queue so that it can hold `new_capacity` messages, keeping the messages it holds.
+    /// Fails with `UnvalidSize` if the queue is already bigger than that.
+    ///
+    /// # Safety
+    /// The storage of the queue may move in memory: no other sender or reader of the queue may be
+    /// used while this is running, and no reference returned by `MessageQueueReader::peek` may be
+    /// alive.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn grow(&mut self, new_capacity: usize) -> Result<(), MessageQueueError> {
+        let internal = &*self.internal;
+        let new_len = new_capacity.checked_add(1).ok_or(MessageQueueError::UnvalidSize)?;
+        let ring = &mut *internal.ring.get();
+        let old_len = ring.len;
+        if new_len < old_len {
+            return Err(MessageQueueError::UnvalidSize);
+        }
+        ring.backing_store.resize(new_len)?;
 
+        let rptr = internal.read_ptr.load(Ordering::Acquire);
+        let count = internal.write_ptr.load(Ordering::Acquire)-rptr;
+        let mut start = ring.slot(rptr);
+        if start+count > old_len {
+            // the messages wrap around the end of the old ring, move the ones at its end to the
+            // end of the new ring
+            let shift = new_len-old_len;
+            ring.backing_store.move_slots(start, start+shift, old_len-start);
+            start += shift;
+        }
+        // the positions must map to the same slots in the new ring
+        ring.len = new_len;
+        internal.read_ptr.store(start, Ordering::Release);
+        internal.write_ptr.store(start+count, Ordering::Release);
+        internal.reserve_ptr.store(start+count, Ordering::Release);
+        if !ring.stamps.is_empty() {
+            ring.reset_stamps(start+count);
+        }
         Ok(())
     }
 
+    /// Empty the queue, dropping the messages still in it, so that it can be reused without
+    /// allocating a new one.
+    ///
+    /// # Safety
+    /// No other sender or reader of the queue may be used while this is running, and no reference
+    /// returned by `MessageQueueReader::peek` (or window from `read_window`) may be alive.
+    pub unsafe fn clear(&mut self) {
+        let internal = &*self.internal;
+        let ring = &mut *internal.ring.get();
+        let wptr = internal.write_ptr.load(Ordering::Acquire);
+        for pos in internal.read_ptr.load(Ordering::Acquire)..wptr {
+            drop(ring.backing_store.get(ring.slot(pos)));
+        }
+        internal.reserve_ptr.store(0, Ordering::Release);
+        internal.write_ptr.store(0, Ordering::Release);
+        internal.read_ptr.store(0, Ordering::Release);
+        if !ring.stamps.is_empty() {
+            ring.reset_stamps(0);
+        }
+    }
+
+    /// Hint the kernel that the queue is read in order, which may help for very big queues used as
+    /// streaming buffers.
+    pub fn hint_sequential(&self) {
+        self.internal.ring().backing_store.advise_sequential();
+    }
+
+    /// Statistics of the queue since its creation, all zeroes unless it was created with
+    /// `new_with_stats`.
+    pub fn stats(&self) -> QueueStats {
+        self.internal.stats()
+    }
+
+    /// Create a reader of the queue.
+    ///
+    /// # Panics
+    /// Only the queues created with `new_spmc` can have several readers at once: the readers of
+    /// the other queues move their messages out without claiming them, so this panics while
+    /// another reader of such a queue is alive.
     pub fn new_reader(&mut self) -> MessageQueueReader<T> {
+        self.internal.add_reader();
+        self.internal.readers_gone.store(false, Ordering::Release);
+        MessageQueueReader {
+            internal: self.internal.clone()
+        }
+    }
+}
+
+impl<T: Copy + 'static> MessageQueueSender<T> {
+    /// Create a queue in the POSIX shared memory object `name` (such as "/my-queue"), which other
+    /// processes can then read with `MessageQueueReader::attach_shared`.
+    /// The object must not exist yet, and it is removed when the queue is dropped in this process
+    /// (the processes attached to it keep using it).
+    /// A shared queue has a single sender and can't grow.
+    ///
+    /// # Safety
+    /// The messages are copied byte for byte between processes, so T must have the same layout in
+    /// all of them (use a `#[repr(C)]` type, built by the same compiler) and must not contain any
+    /// pointer or reference, which would be meaningless in another process.
+    /// The slots start zeroed and the other processes may write anything to them, so any bytes
+    /// must make a valid T (see `BackingStore::new_file`).
+    /// Nothing checks that the processes agree on T besides its size: attaching with another type
+    /// of the same size is undefined behaviour.
+    pub unsafe fn new_shared(name: &str, num_elements: usize) -> Result<MessageQueueSender<T>, MessageQueueError> {
+        if num_elements == 0 {
+            return Err(MessageQueueError::UnvalidSize);
+        }
+        let len = num_elements.checked_add(1).ok_or(MessageQueueError::UnvalidSize)?;
+        let fd = mman::shm_open(name, OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        // the mappings stay valid once the object is closed
+        let internal = MessageQueueInternal::create_shared(fd, name, len);
+        let _ = unistd::close(fd);
+        if internal.is_err() {
+            let _ = mman::shm_unlink(name);
+        }
+        Ok(MessageQueueSender {
+            internal: Arc::new(internal?)
+        })
+    }
+}
+
+impl<T: Copy + 'static> MessageQueueReader<T> {
+    /// Read the queue another process created with `MessageQueueSender::new_shared`.
+    /// Fails with `IncompatibleQueue` if the object doesn't hold a queue of messages of T's size.
+    ///
+    /// # Safety
+    /// See `MessageQueueSender::new_shared` for the (many) requirements on T, which are not
+    /// checked. A shared queue has a single reader as well: no other reader may be alive in any
+    /// process while this one is.
+    pub unsafe fn attach_shared(name: &str) -> Result<MessageQueueReader<T>, MessageQueueError> {
+        let fd = mman::shm_open(name, OFlag::O_RDWR, Mode::empty())?;
+        let internal = MessageQueueInternal::attach_shared(fd);
+        let _ = unistd::close(fd);
+        let internal = internal?;
+        internal.readers.fetch_add(1, Ordering::AcqRel);
+        internal.readers_gone.store(false, Ordering::Release);
+        Ok(MessageQueueReader {
+            internal: Arc::new(internal)
+        })
+    }
+}
+
+impl<T> Clone for MessageQueueSender<T> {
+    /// Create another sender for a multi-producer queue.
+    ///
+    /// # Panics
+    /// Panics if the queue wasn't created with `new_mpsc`, as concurrent sends would then corrupt
+    /// the queue.
+    fn clone(&self) -> Self {
+        assert!(self.internal.multi_producer, "only multi-producer queues can have several senders");
+        self.internal.senders.fetch_add(1, Ordering::AcqRel);
+        MessageQueueSender {
+            internal: self.internal.clone()
+        }
+    }
+}
+
+impl<T> Drop for MessageQueueSender<T> {
+    fn drop(&mut self) {
+        if self.internal.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // let the blocked readers know that nothing else is coming
+            self.internal.notify();
+        }
+    }
+}
+
+impl<T> Clone for MessageQueueReader<T> {
+    /// Create another reader of a multi-consumer queue.
+    ///
+    /// # Panics
+    /// Panics if the queue wasn't created with `new_spmc`, as both readers would then move the
+    /// same messages out (see `MessageQueueSender::new_reader`).
+    fn clone(&self) -> Self {
+        self.internal.add_reader();
         MessageQueueReader {
             internal: self.internal.clone()
         }
     }
 }
 
+impl<T> Drop for MessageQueueReader<T> {
+    fn drop(&mut self) {
+        if self.internal.readers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.internal.readers_gone.store(true, Ordering::Release);
+        }
+    }
+}
+
 impl<T: Sized> MessageQueueReader<T> {
     pub fn available(&self) -> usize {
         self.internal.dist()
     }
 
+    /// Number of messages that can be read before reaching the end of the ring buffer, which is
+    /// less than `available()` when they wrap around it. See `read_window`.
+    pub fn available_contiguous(&self) -> usize {
+        let rpos = self.internal.read_ptr.load(Ordering::Acquire);
+        let ring = self.internal.ring();
+        self.internal.dist().min(ring.len-ring.slot(rpos))
+    }
+
     pub fn is_ready(&self) -> bool {
         self.internal.dist() > 0
     }
 
+    pub fn is_empty(&self) -> bool {
+        !self.is_ready()
+    }
+
+    /// Read the next message of a queue whose readers claim their messages: they race with the
+    /// other readers of a multi-consumer queue, or with the sender of a lossy one, which may drop
+    /// the message to make room (see `MessageQueueSender::send_or_overwrite`).
+    fn read_claimed(&mut self) -> Option<T> {
+        let val = self.internal.claim();
+        if val.is_some() {
+            self.internal.count_read(1);
+        }
+        val
+    }
+
     /// Get current value pointed to by the read_pointer and update the read_pointer.
     /// WARNING: this must never *ever* be called when there is no data available to read
     fn get_current_val(&mut self) -> T {
         let rpos = self.internal.read_ptr.load(Ordering::Acquire);
 
-        let val = self.internal.backing_store.get(rpos);
+        let ring = self.internal.ring();
+        // we are the only reader, and the message stays in its slot until we move past it
+        let val = unsafe { ring.backing_store.get(ring.slot(rpos)) };
 
-        self.internal.read_ptr.store((rpos+1)%self.internal.len, Ordering::Release);
+        self.internal.read_ptr.store(rpos+1, Ordering::Release);
+        self.internal.count_read(1);
         val
     }
 
-    pub fn read(&mut self) -> Option<T> {
+    /// Move up to `max` of the available messages to `out`, and return how many were read.
+    /// Unlike repeated calls to `read`, the read pointer is updated only once for the whole batch.
+    pub fn read_batch(&mut self, out: &mut Vec<T>, max: usize) -> usize {
+        if self.internal.claims_reads() {
+            // the messages have to be claimed one by one
+            let count = out.len();
+            out.extend(iter::from_fn(|| self.read_claimed()).take(max));
+            return out.len()-count;
+        }
+        let count = max.min(self.internal.dist());
+        if count == 0 {
+            return 0;
+        }
+
+        let rpos = self.internal.read_ptr.load(Ordering::Acquire);
+        let ring = self.internal.ring();
+        out.reserve(count);
+        // as for get_current_val
+        for i in 0..count {
+            out.push(unsafe { ring.backing_store.get(ring.slot(rpos+i)) });
+        }
+        self.internal.read_ptr.store(rpos+count, Ordering::Release);
+        self.internal.count_read(count);
+        count
+    }
+
+    /// Borrow the messages available right now in place, without moving them out of the queue.
+    /// They are dropped and removed from the queue once the returned window is dropped.
+    /// The window stops at the end of the ring buffer, so it may hold fewer than `available()`
+    /// messages: call this again until it returns None to see all of them.
+    pub fn read_window(&mut self) -> Option<RingSlice<'_, T>> {
+        assert!(!self.internal.claims_reads(), "lossy and multi-consumer queues can't lend their messages");
+        let len = self.available_contiguous();
+        if len == 0 {
+            return None;
+        }
+        // we are the only reader of the queue (see new_reader), nobody else can take them
+        Some(RingSlice {
+            start: self.internal.read_ptr.load(Ordering::Acquire),
+            len,
+            reader: self
+        })
+    }
+
+    /// Iterate over the messages available right now, without blocking: the iterator ends as soon
+    /// as the queue is empty.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        iter::from_fn(move || self.read())
+    }
+
+    /// Return the next message without removing it from the queue.
+    ///
+    /// # Panics
+    /// Panics for lossy and multi-consumer queues, where the sender or another reader may take
+    /// the message meanwhile.
+    pub fn peek(&self) -> Option<&T> {
+        assert!(!self.internal.claims_reads(), "lossy and multi-consumer queues can't lend their messages");
         if self.is_ready() {
+            let rpos = self.internal.read_ptr.load(Ordering::Acquire);
+            // we are the only reader of the queue (see new_reader), and the senders can't reuse
+            // the slot before we move the read pointer past it
+            let ring = self.internal.ring();
+            Some(unsafe { ring.backing_store.get_ref(ring.slot(rpos)) })
+        } else {
+            None
+        }
+    }
+
+    /// Same as `peek`, but the next message can be modified in place before it is read.
+    pub fn peek_mut(&mut self) -> Option<&mut T> {
+        assert!(!self.internal.claims_reads(), "lossy and multi-consumer queues can't lend their messages");
+        if self.is_ready() {
+            let rpos = self.internal.read_ptr.load(Ordering::Acquire);
+            // as for peek, and we hold the only reference to the reader
+            let ring = self.internal.ring();
+            Some(unsafe { ring.backing_store.get_mut(ring.slot(rpos)) })
+        } else {
+            None
+        }
+    }
+
+    pub fn read(&mut self) -> Option<T> {
+        if self.internal.claims_reads() {
+            self.read_claimed()
+        } else if self.is_ready() {
             Some(self.get_current_val())
         } else {
             None
         }
     }
 
+    /// Wait until a message is available and read it.
+    /// Returns None once all the senders are dropped and the queue has been drained.
+    /// On Linux, the reader sleeps on a futex the senders wake up, and falls back to the backing
+    /// off algorithm elsewhere.
     pub fn blocking_read(&mut self) -> Option<T> {
-        // backing off algorithm
+        if cfg!(target_os = "linux") {
+            self.wait_for(Self::try_blocking_read)
+        } else {
+            backoff(|| self.try_blocking_read())
+        }
+    }
+
+    fn try_blocking_read(&mut self) -> Option<Option<T>> {
+        // check the senders first, so that a message sent right before the last sender
+        // leaves can't be missed
+        let disconnected = self.is_disconnected();
+        match self.read() {
+            Some(x) => Some(Some(x)),
+            None if disconnected => Some(None),
+            None => None
+        }
+    }
+
+    /// Call `attempt` until it succeeds, spinning for a while and then sleeping on the queue futex.
+    fn wait_for<R, F: FnMut(&mut Self) -> Option<R>>(&mut self, mut attempt: F) -> R {
         for _ in 0..50 {
-            if let Some(x) = self.read() {
-                return Some(x);
+            if let Some(x) = attempt(self) {
+                return x;
             }
+            hint::spin_loop();
         }
-        let mut count = 0;
         loop {
-            let dur = match count {
-                0..10 => 35,
-                10..100 => 80,
-                100..500 => 250,
-                _ => 500
-            };
-            thread::sleep(Duration::from_micros(dur));
-            if let Some(x) = self.read() {
-                return Some(x);
+            // register as a sleeper before the last check: a sender either publishes early
+            // enough for the check to see it, or sees us sleeping and wakes us up
+            self.internal.sleepers.fetch_add(1, Ordering::SeqCst);
+            let events = self.internal.events.load(Ordering::SeqCst);
+            let res = attempt(self);
+            if res.is_none() {
+                futex_wait(&self.internal.events, events, self.internal.shared.is_some());
+            }
+            self.internal.sleepers.fetch_sub(1, Ordering::SeqCst);
+            if let Some(x) = res {
+                return x;
+            }
+        }
+    }
+
+    /// Same as `blocking_read`, but gives up with `TimedOut` if no message arrived within `dur`.
+    /// This uses the backing off algorithm, sleeping no further than the deadline.
+    pub fn read_timeout(&mut self, dur: Duration) -> Result<Option<T>, MessageQueueError> {
+        let deadline = Instant::now() + dur;
+        backoff_until(Some(deadline), || self.try_blocking_read()).ok_or(MessageQueueError::TimedOut)
+    }
+
+    /// Statistics of the queue since its creation, see `MessageQueueSender::stats`.
+    pub fn stats(&self) -> QueueStats {
+        self.internal.stats()
+    }
+
+    /// Returns true when every sender of the queue has been dropped: no new message can arrive.
+    pub fn is_disconnected(&self) -> bool {
+        self.internal.senders.load(Ordering::Acquire) == 0
+    }
+}
+
+/// Contiguous run of messages borrowed from a queue by `MessageQueueReader::read_window`.
+/// The messages are read (and dropped) when it goes out of scope.
+pub struct RingSlice<'a, T> {
+    reader: &'a mut MessageQueueReader<T>,
+    /// Position of the first message
+    start: usize,
+    len: usize
+}
+
+impl<'a, T> Deref for RingSlice<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // the senders can't write to these slots before we move the read pointer past them
+        let ring = self.reader.internal.ring();
+        unsafe { ring.backing_store.get_slice(ring.slot(self.start), self.len) }
+    }
+}
+
+impl<'a, T> DerefMut for RingSlice<'a, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // as for deref, and we hold the only reference to the reader
+        let ring = self.reader.internal.ring();
+        unsafe { ring.backing_store.get_slice_mut(ring.slot(self.start), self.len) }
+    }
+}
+
+impl<'a, T> Drop for RingSlice<'a, T> {
+    fn drop(&mut self) {
+        let internal = &self.reader.internal;
+        let ring = internal.ring();
+        // the messages are ours until we move the read pointer past them
+        unsafe { ring.backing_store.drop_slots(ring.slot(self.start), self.len) };
+        internal.read_ptr.store(self.start+self.len, Ordering::Release);
+        internal.count_read(self.len);
+    }
+}
+
+/// Call `attempt` until it succeeds, spinning at first and then sleeping longer and longer
+/// between attempts.
+fn backoff<R, F: FnMut() -> Option<R>>(attempt: F) -> R {
+    backoff_until(None, attempt).unwrap()
+}
+
+/// Same as `backoff`, but gives up and returns None once `deadline` is reached.
+fn backoff_until<R, F: FnMut() -> Option<R>>(deadline: Option<Instant>, mut attempt: F) -> Option<R> {
+    // backing off algorithm
+    for _ in 0..50 {
+        if let Some(x) = attempt() {
+            return Some(x);
+        }
+    }
+    let mut count = 0;
+    loop {
+        let mut dur = Duration::from_micros(match count {
+            0..10 => 35,
+            10..100 => 80,
+            100..500 => 250,
+            _ => 500
+        });
+        if let Some(deadline) = deadline {
+            // the time spent spinning counts too
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
             }
-            count += 1;
+            dur = dur.min(deadline - now);
         }
+        thread::sleep(dur);
+        if let Some(x) = attempt() {
+            return Some(x);
+        }
+        count += 1;
     }
 }
 
+/// Wait until one of `readers` has a message available and return its index, the lowest one if
+/// several are ready.
+/// This uses the backing off algorithm, and gives up with None once `timeout` has elapsed.
+/// Without a timeout, beware that this waits forever if all the senders are gone.
+pub fn wait_any<T>(readers: &mut [&mut MessageQueueReader<T>], timeout: Option<Duration>) -> Option<usize> {
+    let deadline = timeout.map(|dur| Instant::now() + dur);
+    backoff_until(deadline, || readers.iter().position(|reader| reader.is_ready()))
+}
+
+/// Futex operation `op` on a word private to the process, unless it is `shared` with others.
+#[cfg(target_os = "linux")]
+fn futex_op(op: libc::c_int, shared: bool) -> libc::c_int {
+    if shared { op } else { op | libc::FUTEX_PRIVATE_FLAG }
+}
+
+/// Sleep until `word` is woken up, unless its value is no longer `expected`.
+/// Spurious wake-ups are possible, the caller has to check its condition again.
+#[cfg(target_os = "linux")]
+fn futex_wait(word: &AtomicU32, expected: u32, shared: bool) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), futex_op(libc::FUTEX_WAIT, shared), expected, ptr::null::<libc::timespec>());
+    }
+}
+
+/// Wake up every thread sleeping on `word`.
+#[cfg(target_os = "linux")]
+fn futex_wake(word: &AtomicU32, shared: bool) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), futex_op(libc::FUTEX_WAKE, shared), i32::MAX);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn futex_wait(_word: &AtomicU32, _expected: u32, _shared: bool) {
+    thread::sleep(Duration::from_micros(500));
+}
+
+#[cfg(not(target_os = "linux"))]
+fn futex_wake(_word: &AtomicU32, _shared: bool) {}
+
 /// Create a Message queue with a sender and a reader.
 /// This is very akin to a ruststd channel.
-pub fn message_queue<T: Clone>(num_elements: usize) -> Result<(MessageQueueSender<T>, MessageQueueReader<T>), MessageQueueError> {
+pub fn message_queue<T>(num_elements: usize) -> Result<(MessageQueueSender<T>, MessageQueueReader<T>), MessageQueueError> {
     let mut sender = MessageQueueSender::new(num_elements)?;
     let reader = sender.new_reader();
     Ok((sender, reader))
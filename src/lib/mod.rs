@@ -1,4 +1,8 @@
+pub mod aho;
 pub mod http;
-mod backingstore;
+pub mod backingstore;
 pub mod messagequeue;
 pub mod parser;
+pub mod router;
+pub mod server;
+pub mod threadpool;
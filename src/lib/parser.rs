@@ -1,32 +1,81 @@
-use std::marker::PhantomData;
-
 pub trait Parser where Self: Sized {
     /// Consume data until it matches a given pattern.
-    fn read_until<'cs>(self, end_pattern: &'cs [u8]) -> Combine<ReaderUntil<'cs>, Self> {
-        Combine::new(ReaderUntil {
-            end_pattern
-        }, self)
+    /// The pattern itself is left in the input stream.
+    fn read_until<'cs>(self, end_pattern: &'cs [u8]) -> Combine<Self, ReaderUntil<'cs>> {
+        Combine::new(self, ReaderUntil::new(end_pattern))
+    }
+
+    /// Same as read_until, but also consume the pattern (which is not part of the output).
+    fn read_until_consuming<'cs>(self, end_pattern: &'cs [u8]) -> Combine<Self, ReaderUntil<'cs>> {
+        Combine::new(self, ReaderUntil::consuming(end_pattern))
     }
 
     /// Read while the predicate holds true on the data the parser feeds it.
     /// The predicate must return how much data it should consume.
     /// If zero, we stop parsing, otherwise we try consuming data again.
-    fn consume_while_predicate(self, predicate: for<'a> fn(&'a [u8]) -> Result<usize, ParserError>) -> Combine<Consumer, Self>  {
-        Combine::new(Consumer {
-            predicate
-        }, self)
+    fn consume_while_predicate(self, predicate: for<'a> fn(&'a [u8]) -> Result<usize, ParserError>) -> Combine<Self, Consumer>  {
+        Combine::new(self, Consumer::new(predicate))
+    }
+
+    /// Same as consume_while_predicate, but the predicate also gets the position (in the whole
+    /// input) of the data it is fed.
+    fn consume_while_predicate_pos(self, predicate: for<'a> fn(&'a [u8], usize) -> Result<usize, ParserError>) -> Combine<Self, Consumer> {
+        Combine::new(self, Consumer::with_pos(predicate))
     }
 
     /// Read all the remaining input stream
-    fn consume_to_end(self) -> Combine<ConsumerToEnd, Self> {
-        Combine::new(ConsumerToEnd {}, self)
+    fn consume_to_end(self) -> Combine<Self, ConsumerToEnd> {
+        Combine::new(self, ConsumerToEnd::new())
+    }
+
+    /// Peek `num` bytes, without consuming them.
+    fn peek(self, num: usize) -> Combine<Self, Peeker> {
+        Combine::new(self, Peeker::new(num))
+    }
+
+    /// Consume `num` bytes.
+    fn take(self, num: usize) -> Combine<Self, Take> {
+        Combine::new(self, Take::new(num))
+    }
+
+    /// Evaluate `inner` as many times as possible (possibly zero).
+    fn many<P: Parser>(self, inner: P) -> Combine<Self, Many<P>> {
+        Combine::new(self, Many::new(inner))
+    }
+
+    /// Same as many, but `inner` must succeed at least once.
+    fn many1<P: Parser>(self, inner: P) -> Combine<Self, Many<P>> {
+        Combine::new(self, Many::new1(inner))
     }
 
-    /// Peak `num` bytes.
-    fn peek(self, num: usize) -> Combine<Peeker, Self> {
-        Combine::new(Peeker {
-            peek_number: num
-        }, self)
+    /// Evaluate `inner` if possible, without failing when it doesn't match.
+    fn optional<P: Parser>(self, inner: P) -> Combine<Self, Optional<P>> {
+        Combine::new(self, Optional::new(inner))
+    }
+
+    /// Evaluate `inner`, labelling its errors with `label`.
+    fn context<P: Parser>(self, label: &'static str, inner: P) -> Combine<Self, Context<P>> {
+        Combine::new(self, Context::new(label, inner))
+    }
+
+    /// Fail unless the whole input was consumed.
+    fn eof(self) -> Combine<Self, Eof> {
+        Combine::new(self, Eof::new())
+    }
+
+    /// Evaluate `inner`, failing if it consumes more than `max_bytes`.
+    fn within<P: Parser>(self, max_bytes: usize, inner: P) -> Combine<Self, Within<P>> {
+        Combine::new(self, Within::new(max_bytes, inner))
+    }
+
+    /// Consume `pattern`, failing if the input doesn't start with it.
+    fn expect<'cs>(self, pattern: &'cs [u8]) -> Combine<Self, Expect<'cs>> {
+        Combine::new(self, Expect::new(pattern))
+    }
+
+    /// Read a decimal number.
+    fn number(self) -> Combine<Self, Number> {
+        Combine::new(self, Number::new())
     }
 }
 
@@ -34,23 +83,51 @@ pub trait Parser where Self: Sized {
 pub trait ParserEvaluator<'a> {
     type Output;
 
-    fn evaluate(&'a self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError>;
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError>;
 }
 
 
+/// Run `parser` over the whole `input`: failing with InvalidData if it doesn't consume all of it.
+pub fn parse<'a, P: ParserEvaluator<'a>>(parser: &P, input: &'a [u8]) -> Result<P::Output, ParserError> {
+    let mut state = ParserState::new();
+    let res = parser.evaluate(input, &mut state)?;
+    if state.pos != input.len() {
+        return Err(ParserError::InvalidData);
+    }
+    Ok(res)
+}
+
+/// Same as parse, but ignores what `parser` left unparsed, returning how much it consumed along its
+/// output.
+pub fn parse_partial<'a, P: ParserEvaluator<'a>>(parser: &P, input: &'a [u8]) -> Result<(P::Output, usize), ParserError> {
+    let mut state = ParserState::new();
+    let res = parser.evaluate(input, &mut state)?;
+    Ok((res, state.pos))
+}
+
+
+#[derive(Default)]
 pub struct ParserState {
     pos: usize
 }
 
 impl ParserState {
-    fn index(&self, string: &[u8], index: usize) -> Result<u8, ParserError> {
-        if index <= self.pos {
-            Err(ParserError::OutOfBoundsAccess)
-        } else {
-            Ok(string[self.pos])
+    pub fn new() -> ParserState {
+        ParserState {
+            pos: 0
         }
     }
 
+    /// Current position in the input
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// Byte at `index` in `string`
+    pub fn index(&self, string: &[u8], index: usize) -> Result<u8, ParserError> {
+        string.get(index).copied().ok_or(ParserError::OutOfBoundsAccess)
+    }
+
     fn index_size<'a>(&self, string: &'a [u8], start: usize, size: usize) -> Result<&'a [u8], ParserError> {
         let end = start.checked_add(size);
         match end {
@@ -65,7 +142,8 @@ impl ParserState {
         }
     }
 
-    fn get_current(&self, string: &[u8]) -> Result<u8, ParserError> {
+    /// Byte at the current position
+    pub fn get_current(&self, string: &[u8]) -> Result<u8, ParserError> {
         self.index(string, self.pos)
     }
 
@@ -83,18 +161,47 @@ pub enum InvalidStateError {
     EOF
 }
 
+/// The part of the input that crossed one of the bounds set by the caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Url,
+    Headers,
+    Body
+}
+
 #[derive(Debug)]
 pub enum ParserError {
     OutOfBoundsAccess,
     InvalidState(InvalidStateError),
     InvalidData,
     Overflow,
-    UTFError(std::string::FromUtf8Error)
+    UTFError(std::str::Utf8Error),
+    /// The input is bigger than what the caller is willing to parse
+    LimitExceeded(Limit),
+    /// Error raised by a parser wrapped in a Context
+    Context(&'static str, Box<ParserError>)
+}
+
+impl ParserError {
+    /// Whether the error must stop the evaluation instead of letting the parser try something else
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            ParserError::InvalidState(_) => true,
+            ParserError::Context(_, e) => e.is_fatal(),
+            _ => false
+        }
+    }
+}
+
+impl std::convert::From<std::str::Utf8Error> for ParserError {
+    fn from(data: std::str::Utf8Error) -> ParserError {
+        ParserError::UTFError(data)
+    }
 }
 
 impl std::convert::From<std::string::FromUtf8Error> for ParserError {
     fn from(data: std::string::FromUtf8Error) -> ParserError {
-        ParserError::UTFError(data)
+        ParserError::UTFError(data.utf8_error())
     }
 }
 
@@ -108,7 +215,7 @@ impl<A: Parser, B: Parser> Parser for Combine<A, B> {}
 impl<'a, A: Parser+ParserEvaluator<'a>, B: Parser+ParserEvaluator<'a>> ParserEvaluator<'a> for Combine<A, B> {
     type Output = (A::Output, B::Output);
 
-    fn evaluate(&'a self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
         let res_a = self.pa.evaluate(string, state)?;
         let res_b = self.pb.evaluate(string, state)?;
         Ok((res_a, res_b))
@@ -116,7 +223,8 @@ impl<'a, A: Parser+ParserEvaluator<'a>, B: Parser+ParserEvaluator<'a>> ParserEva
 }
 
 impl<A: Parser, B: Parser> Combine<A, B> {
-    fn new(pa: A, pb: B) -> Self {
+    /// Evaluate `pa` then `pb`
+    pub fn new(pa: A, pb: B) -> Self {
         Combine {
             pa,
             pb
@@ -125,6 +233,38 @@ impl<A: Parser, B: Parser> Combine<A, B> {
 }
 
 
+/// Define a parser evaluating its parsers in sequence and returning their outputs as a flat tuple,
+/// along with the function building it.
+macro_rules! impl_seq {
+    ($name:ident, $func:ident, $($p:ident: $t:ident),*) => {
+        pub struct $name<$($t),*> where $($t: Parser),* {
+            $($p: $t),*
+        }
+
+        impl<$($t: Parser),*> Parser for $name<$($t),*> {}
+        impl<'a, $($t: Parser+ParserEvaluator<'a>),*> ParserEvaluator<'a> for $name<$($t),*> {
+            type Output = ($($t::Output),*);
+
+            fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+                Ok(($(self.$p.evaluate(string, state)?),*))
+            }
+        }
+
+        /// Evaluate the parsers one after the other, like nested Combines but with a flat output:
+        /// `seq3(a, b, c)` gives `(a_out, b_out, c_out)` instead of `((a_out, b_out), c_out)`.
+        pub fn $func<$($t: Parser),*>($($p: $t),*) -> $name<$($t),*> {
+            $name {
+                $($p),*
+            }
+        }
+    }
+}
+
+impl_seq!(Seq3, seq3, pa: A, pb: B, pc: C);
+impl_seq!(Seq4, seq4, pa: A, pb: B, pc: C, pd: D);
+
+
+#[derive(Debug, PartialEq)]
 pub enum OneOf<A, B> {
     First(A),
     Second(B)
@@ -139,13 +279,16 @@ impl<A: Parser, B: Parser> Parser for TryOr<A, B> {}
 impl<'a, A: Parser+ParserEvaluator<'a>, B: Parser+ParserEvaluator<'a>> ParserEvaluator<'a> for TryOr<A, B> {
     type Output = OneOf<A::Output, B::Output>;
 
-    fn evaluate(&'a self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        let old_pos = state.pos;
         match self.pa.evaluate(string, state) {
             Ok(x) => Ok(OneOf::First(x)),
             Err(e) => {
-                if let ParserError::InvalidState(_) = e {
+                if e.is_fatal() {
                     Err(e)
                 } else {
+                    // pa may have consumed some data before failing, pb must start from scratch
+                    state.pos = old_pos;
                     Ok(OneOf::Second(self.pb.evaluate(string, state)?))
                 }
             }
@@ -154,7 +297,7 @@ impl<'a, A: Parser+ParserEvaluator<'a>, B: Parser+ParserEvaluator<'a>> ParserEva
 }
 
 impl<A: Parser, B: Parser> TryOr<A, B> {
-    fn new(pa: A, pb: B) -> Self {
+    pub fn new(pa: A, pb: B) -> Self {
         TryOr {
             pa,
             pb
@@ -163,27 +306,206 @@ impl<A: Parser, B: Parser> TryOr<A, B> {
 }
 
 
+/// Some(x) if the parser succeeds, None (leaving the position untouched) if it fails with a
+/// recoverable error.
+pub struct Optional<P> where P: Parser {
+    inner: P
+}
+
+impl<P: Parser> Optional<P> {
+    pub fn new(inner: P) -> Self {
+        Optional {
+            inner
+        }
+    }
+}
+
+impl<P: Parser> Parser for Optional<P> {}
+impl<'a, P: Parser+ParserEvaluator<'a>> ParserEvaluator<'a> for Optional<P> {
+    type Output = Option<P::Output>;
+
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        let old_pos = state.pos;
+        match self.inner.evaluate(string, state) {
+            Ok(x) => Ok(Some(x)),
+            Err(e) if e.is_fatal() => Err(e),
+            Err(_) => {
+                state.pos = old_pos;
+                Ok(None)
+            }
+        }
+    }
+}
+
+
+/// Wrap the errors of a parser with a label, to know where a parser made of many others failed
+pub struct Context<P> where P: Parser {
+    label: &'static str,
+    inner: P
+}
+
+impl<P: Parser> Context<P> {
+    pub fn new(label: &'static str, inner: P) -> Self {
+        Context {
+            label,
+            inner
+        }
+    }
+}
+
+impl<P: Parser> Parser for Context<P> {}
+impl<'a, P: Parser+ParserEvaluator<'a>> ParserEvaluator<'a> for Context<P> {
+    type Output = P::Output;
+
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        self.inner.evaluate(string, state).map_err(|e| ParserError::Context(self.label, Box::new(e)))
+    }
+}
+
+
+/// Cap how much a parser may consume, failing with InvalidData (and restoring the position) if it
+/// goes further.
+/// The parser only sees the input up to one byte past the cap, so that it can't waste time
+/// scanning further than that either.
+pub struct Within<P> where P: Parser {
+    max_bytes: usize,
+    inner: P
+}
+
+impl<P: Parser> Within<P> {
+    pub fn new(max_bytes: usize, inner: P) -> Self {
+        Within {
+            max_bytes,
+            inner
+        }
+    }
+}
+
+impl<P: Parser> Parser for Within<P> {}
+impl<'a, P: Parser+ParserEvaluator<'a>> ParserEvaluator<'a> for Within<P> {
+    type Output = P::Output;
+
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        let old_pos = state.pos;
+        let end = old_pos.saturating_add(self.max_bytes).saturating_add(1).min(string.len());
+        let res = match self.inner.evaluate(&string[..end], state) {
+            Ok(_) if state.pos-old_pos > self.max_bytes => Err(ParserError::InvalidData),
+            // the parser wanted more than what we let it see
+            Err(ParserError::InvalidState(InvalidStateError::EOF)) if end < string.len() => Err(ParserError::InvalidData),
+            res => res
+        };
+        if res.is_err() {
+            state.pos = old_pos;
+        }
+        res
+    }
+}
+
+
+/// Repeat a parser until it fails (with a recoverable error) or the input is exhausted, the
+/// position being restored to the end of the last success.
+pub struct Many<P> where P: Parser {
+    inner: P,
+    min: usize
+}
+
+impl<P: Parser> Many<P> {
+    /// Zero or more times
+    pub fn new(inner: P) -> Self {
+        Many {
+            inner,
+            min: 0
+        }
+    }
+
+    /// One or more times
+    pub fn new1(inner: P) -> Self {
+        Many {
+            inner,
+            min: 1
+        }
+    }
+}
+
+impl<P: Parser> Parser for Many<P> {}
+impl<'a, P: Parser+ParserEvaluator<'a>> ParserEvaluator<'a> for Many<P> {
+    type Output = Vec<P::Output>;
+
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        let mut res = Vec::new();
+        while state.pos < string.len() {
+            let old_pos = state.pos;
+            match self.inner.evaluate(string, state) {
+                Ok(x) => {
+                    res.push(x);
+                    if state.pos == old_pos {
+                        // we would loop forever
+                        break;
+                    }
+                },
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) => {
+                    state.pos = old_pos;
+                    if res.len() < self.min {
+                        return Err(e);
+                    }
+                    break;
+                }
+            }
+        }
+        if res.len() < self.min {
+            return Err(ParserError::InvalidState(InvalidStateError::EOF));
+        }
+        Ok(res)
+    }
+}
+
 
+/// Read everything up to `end_pattern` (or up to the end of the input if it's not found)
 pub struct ReaderUntil<'cs> {
-    end_pattern: &'cs [u8]
+    end_pattern: &'cs [u8],
+    /// Move past `end_pattern` when it is found
+    consume: bool
+}
+
+impl<'cs> ReaderUntil<'cs> {
+    /// Stop before `end_pattern`
+    pub fn new(end_pattern: &'cs [u8]) -> Self {
+        ReaderUntil {
+            end_pattern,
+            consume: false
+        }
+    }
+
+    /// Stop after `end_pattern`
+    pub fn consuming(end_pattern: &'cs [u8]) -> Self {
+        ReaderUntil {
+            end_pattern,
+            consume: true
+        }
+    }
 }
 
 impl<'cs> Parser for ReaderUntil<'cs> {}
 impl<'a, 'cs> ParserEvaluator<'a> for ReaderUntil<'cs> {
     type Output = &'a [u8];
 
-    fn evaluate(&'a self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
         let old_pos = state.pos;
         let len = string.len();
         while !string[state.pos..].starts_with(self.end_pattern) {
-            state.pos += 1;
             if state.pos == len {
                 // EOF
                 return Ok(&string[old_pos..state.pos]);
             }
+            state.pos += 1;
         }
 
-        Ok(&string[old_pos..state.pos])
+        let res = &string[old_pos..state.pos];
+        if self.consume {
+            state.pos += self.end_pattern.len();
+        }
+        Ok(res)
     }
 }
 
@@ -191,47 +513,113 @@ pub struct Peeker {
     peek_number: usize
 }
 
+impl Peeker {
+    pub fn new(peek_number: usize) -> Self {
+        Peeker {
+            peek_number
+        }
+    }
+}
+
 impl Parser for Peeker {}
 impl<'a> ParserEvaluator<'a> for Peeker {
     type Output = &'a [u8];
 
-    fn evaluate(&'a self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
-        let res = state.get_n(string, self.peek_number)?;
-        state.pos += self.peek_number;
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        state.get_n(string, self.peek_number)
+    }
+}
+
+pub struct Take {
+    take_number: usize
+}
+
+impl Take {
+    pub fn new(take_number: usize) -> Self {
+        Take {
+            take_number
+        }
+    }
+}
+
+impl Parser for Take {}
+impl<'a> ParserEvaluator<'a> for Take {
+    type Output = &'a [u8];
+
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        let res = state.get_n(string, self.take_number)?;
+        state.pos += self.take_number;
         Ok(res)
     }
 }
 
 
+#[derive(Default)]
 pub struct ConsumerToEnd {}
 
+impl ConsumerToEnd {
+    pub fn new() -> Self {
+        ConsumerToEnd {}
+    }
+}
+
 impl Parser for ConsumerToEnd {}
 impl<'a> ParserEvaluator<'a> for ConsumerToEnd {
     type Output = &'a [u8];
 
-    fn evaluate(&'a self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
         let res = state.get_n(string, string.len()-state.pos)?;
         state.pos = string.len();
         Ok(res)
     }
 }
 
+enum Predicate {
+    Data(for<'b> fn(&'b [u8]) -> Result<usize, ParserError>),
+    /// Also takes the position of the data in the input
+    DataPos(for<'b> fn(&'b [u8], usize) -> Result<usize, ParserError>)
+}
+
 pub struct Consumer {
-    predicate: for<'b> fn(&'b [u8]) -> Result<usize, ParserError>
+    predicate: Predicate
+}
+
+impl Consumer {
+    pub fn new(predicate: for<'b> fn(&'b [u8]) -> Result<usize, ParserError>) -> Self {
+        Consumer {
+            predicate: Predicate::Data(predicate)
+        }
+    }
+
+    /// The predicate also gets the position of the data it is fed, from the start of the input
+    pub fn with_pos(predicate: for<'b> fn(&'b [u8], usize) -> Result<usize, ParserError>) -> Self {
+        Consumer {
+            predicate: Predicate::DataPos(predicate)
+        }
+    }
 }
 
 impl Parser for Consumer {}
 impl<'a> ParserEvaluator<'a> for Consumer {
     type Output = &'a [u8];
 
-    fn evaluate(&'a self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
         let mut delta = 0;
-        loop {
-            let offset = (self.predicate)(&string[state.pos+delta..])?;
+        // there is nothing left to consume once we reach the end of the input
+        while state.pos+delta < string.len() {
+            let pos = state.pos+delta;
+            let offset = match self.predicate {
+                Predicate::Data(predicate) => predicate(&string[pos..])?,
+                Predicate::DataPos(predicate) => predicate(&string[pos..], pos)?
+            };
             if offset == 0 {
                 // time to stop parsing
                 break;
             }
+            if offset > string.len()-pos {
+                // the predicate asked for more than what's left
+                return Err(ParserError::OutOfBoundsAccess);
+            }
             delta += offset;
         }
         let res = &string[state.pos..state.pos+delta];
@@ -241,16 +629,105 @@ impl<'a> ParserEvaluator<'a> for Consumer {
 }
 
 
+fn is_digit(data: &[u8]) -> Result<usize, ParserError> {
+    Ok(data.first().map_or(0, |c| c.is_ascii_digit() as usize))
+}
+
+fn is_hex_digit(data: &[u8]) -> Result<usize, ParserError> {
+    Ok(data.first().map_or(0, |c| c.is_ascii_hexdigit() as usize))
+}
+
+/// Read an unsigned number, failing with InvalidData if there is no digit and with Overflow if it
+/// doesn't fit in a u64.
+pub struct Number {
+    radix: u32
+}
+
+impl Number {
+    /// Base 10
+    pub fn new() -> Self {
+        Number {
+            radix: 10
+        }
+    }
+
+    /// Base 16, case insensitive and without any '0x' prefix
+    pub fn hex() -> Self {
+        Number {
+            radix: 16
+        }
+    }
+}
+
+impl Default for Number {
+    fn default() -> Self {
+        Number::new()
+    }
+}
+
+impl Parser for Number {}
+impl<'a> ParserEvaluator<'a> for Number {
+    type Output = u64;
+
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        let predicate = if self.radix == 16 { is_hex_digit } else { is_digit };
+        let digits = Consumer::new(predicate).evaluate(string, state)?;
+        if digits.is_empty() {
+            return Err(ParserError::InvalidData);
+        }
+        digits.iter().try_fold(0u64, |acc, c| {
+            // the digits were already validated by the predicate
+            let digit = (*c as char).to_digit(self.radix).unwrap() as u64;
+            acc.checked_mul(self.radix as u64)
+                .and_then(|acc| acc.checked_add(digit))
+                .ok_or(ParserError::Overflow)
+        })
+    }
+}
+
+
+/// Succeed only at the end of the input, failing with InvalidData otherwise
+#[derive(Default)]
+pub struct Eof {}
+
+impl Eof {
+    pub fn new() -> Self {
+        Eof {}
+    }
+}
+
+impl Parser for Eof {}
+impl<'a> ParserEvaluator<'a> for Eof {
+    type Output = ();
+
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        if state.pos == string.len() {
+            Ok(())
+        } else {
+            Err(ParserError::InvalidData)
+        }
+    }
+}
+
+
 /// Return true if the substring is matched, false otherwise
 pub struct Match<'cs> {
     pattern: &'cs [u8]
 }
 
+impl<'cs> Match<'cs> {
+    pub fn new(pattern: &'cs [u8]) -> Self {
+        Match {
+            pattern
+        }
+    }
+}
+
 impl<'cs> Parser for Match<'cs> {}
 impl<'a, 'cs> ParserEvaluator<'a> for Match<'cs> {
     type Output = bool;
 
-    fn evaluate(&'a self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
         if string.len()-state.pos < self.pattern.len() {
             Err(ParserError::InvalidState(InvalidStateError::EOF))
         } else {
@@ -263,3 +740,34 @@ impl<'a, 'cs> ParserEvaluator<'a> for Match<'cs> {
         }
     }
 }
+
+/// Consume `pattern`, failing with InvalidData (without moving) if the input doesn't start with
+/// it, or with EOF if the input stops before the end of the pattern while matching it so far
+pub struct Expect<'cs> {
+    pattern: &'cs [u8]
+}
+
+impl<'cs> Expect<'cs> {
+    pub fn new(pattern: &'cs [u8]) -> Self {
+        Expect {
+            pattern
+        }
+    }
+}
+
+impl<'cs> Parser for Expect<'cs> {}
+impl<'a, 'cs> ParserEvaluator<'a> for Expect<'cs> {
+    type Output = ();
+
+    fn evaluate(&self, string: &'a [u8], state: &mut ParserState) -> Result<Self::Output, ParserError> {
+        let rest = &string[state.pos..];
+        if rest.starts_with(self.pattern) {
+            state.pos += self.pattern.len();
+            Ok(())
+        } else if self.pattern.starts_with(rest) {
+            Err(ParserError::InvalidState(InvalidStateError::EOF))
+        } else {
+            Err(ParserError::InvalidData)
+        }
+    }
+}
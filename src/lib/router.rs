@@ -0,0 +1,71 @@
+use crate::lib::aho::AhoTree;
+use crate::lib::http::{HTTPVerb, HttpQuery, HttpResponse};
+
+/// A handler for the queries of a route, boxed so that a router can hold different closures.
+pub type Handler = Box<dyn Fn(HttpQuery) -> HttpResponse + Send + Sync>;
+
+/// Dispatch queries to handlers according to their verb and path.
+/// A handler registered for a path also handles every path it is a prefix of, the longest
+/// registered prefix winning: `/users` handles `/users/42`.
+/// Beware that the match is done byte by byte, so `/users` handles `/usersettings` too, register
+/// `/users/` if that matters.
+pub struct Router<H> {
+    routes: AhoTree<H>,
+    /// Handler of the queries no route matches
+    fallback: Option<H>
+}
+
+impl<H> Router<H> {
+    pub fn new() -> Self {
+        Router {
+            routes: AhoTree::new(),
+            fallback: None
+        }
+    }
+
+    /// Handle the queries matching no route with `handler` rather than a plain 404.
+    pub fn set_fallback(&mut self, handler: H) {
+        self.fallback = Some(handler);
+    }
+
+    fn key(verb: &HTTPVerb, path: &str) -> Vec<u8> {
+        format!("{} {}", verb.as_str(), path).into_bytes()
+    }
+
+    /// Register `handler` for the queries on `path` (or below) with `verb`, replacing (and
+    /// returning) the previous handler for that route, if any.
+    pub fn add(&mut self, verb: HTTPVerb, path: &str, handler: H) -> Option<H> {
+        self.routes.insert_rule(&Router::<H>::key(&verb, path), handler)
+    }
+
+    /// Whether a handler was registered for exactly this route, prefixes don't count.
+    /// Unlike route, this tells a path that exists for other verbs only (405) from an unknown one
+    /// (404).
+    pub fn has_exact(&self, verb: HTTPVerb, path: &str) -> bool {
+        self.routes.get_ref(&Router::<H>::key(&verb, path)).is_some()
+    }
+
+    /// Handler of the longest route matching the query
+    pub fn route(&self, q: &HttpQuery) -> Option<&H> {
+        // verbs can't contain spaces, so a matching route always has the same verb as the query
+        self.routes.longest_prefix_match_ref(&Router::<H>::key(&q.verb, q.path)).map(|(_, handler)| handler)
+    }
+}
+
+impl<H: Fn(HttpQuery) -> HttpResponse> Router<H> {
+    /// Answer `q` with the handler of its route, or the fallback handler (a 404 if there is none)
+    /// when no route matches.
+    /// This makes the router usable as the handler of `server::serve`.
+    pub fn handle(&self, q: HttpQuery) -> HttpResponse {
+        match self.route(&q).or(self.fallback.as_ref()) {
+            Some(handler) => handler(q),
+            None => HttpResponse::not_found()
+        }
+    }
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Self {
+        Router::new()
+    }
+}
@@ -0,0 +1,166 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::lib::http::{HttpQuery, HttpResponse, ParseLimits};
+use crate::lib::parser::{InvalidStateError, ParserError};
+use crate::lib::threadpool::ThreadPool;
+
+/// Number of threads handling the connections
+const WORKERS: usize = 4;
+/// Number of accepted connections that can wait for a free worker
+const BACKLOG: usize = 256;
+/// How long we wait for a client to send the rest of its query
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long an idle connection may wait for its next query, it holds a worker meanwhile
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a worker waiting for a client checks whether the server is stopping
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Room left for the parts of a query ParseLimits doesn't cover (verb, version, blank lines...)
+const QUERY_SLACK: usize = 1024;
+
+/// A running server, see `serve`.
+/// Dropping the handle leaves the server running in the background.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    acceptor: JoinHandle<io::Result<()>>
+}
+
+impl ServerHandle {
+    /// Address the server listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Ask the server to stop: it accepts no new connection, answers the queries it already
+    /// received (closing the connections after them) and closes the idle connections.
+    /// This doesn't wait for the server to be done, see `join`.
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::Release);
+        // wake the acceptor up so that it sees the flag, it may not get another client for a while
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST)
+            });
+        }
+        let _ = TcpStream::connect(addr);
+    }
+
+    /// Wait for the server to stop, after a `shutdown` or because the listener failed (in which
+    /// case its error is returned).
+    pub fn join(self) -> io::Result<()> {
+        self.acceptor.join().unwrap_or_else(|_| Err(io::Error::other("the server thread panicked")))
+    }
+}
+
+/// Listen on `addr` and answer every query with `handler`, in the background.
+/// The connections are kept alive as long as the clients want to, and the server runs until it
+/// is stopped with the returned handle.
+pub fn serve<F>(addr: &str, handler: F) -> io::Result<ServerHandle>
+    where F: Fn(HttpQuery) -> HttpResponse + Send + Sync + 'static {
+    serve_listener(TcpListener::bind(addr)?, handler)
+}
+
+/// Same as serve, on an already bound listener.
+pub fn serve_listener<F>(listener: TcpListener, handler: F) -> io::Result<ServerHandle>
+    where F: Fn(HttpQuery) -> HttpResponse + Send + Sync + 'static {
+    let addr = listener.local_addr()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopping = stop.clone();
+    let mut pool = ThreadPool::new(WORKERS, BACKLOG, move |mut stream: TcpStream| {
+        // there is nobody to report the error to, the client will see the connection closing
+        let _ = handle_connection(&mut stream, &handler, &stopping);
+    })?;
+
+    let stopping = stop.clone();
+    let acceptor = thread::spawn(move || {
+        for stream in listener.incoming() {
+            // shutdown connects to us right after setting the flag
+            if stopping.load(Ordering::Acquire) {
+                break;
+            }
+            match stream {
+                Ok(stream) => pool.execute(stream)?,
+                // the client may have given up before we accepted it
+                Err(_) => continue
+            }
+        }
+        // let the workers finish with the connections they have
+        pool.shutdown();
+        Ok(())
+    });
+
+    Ok(ServerHandle {
+        addr,
+        stop,
+        acceptor
+    })
+}
+
+/// Answer the queries sent on `stream` until the client closes the connection or asks us to, or
+/// the server stops.
+fn handle_connection<F: Fn(HttpQuery) -> HttpResponse>(stream: &mut TcpStream, handler: &F, stop: &AtomicBool) -> io::Result<()> {
+    // wake up regularly to notice the server stopping
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let limits = ParseLimits::default();
+    // the parser rejects a query as soon as it crosses a limit, this only guards the rest of it
+    let max_query = limits.max_url_len.saturating_add(limits.max_header_bytes)
+        .saturating_add(limits.max_body_bytes).saturating_add(QUERY_SLACK);
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let mut waited = Duration::ZERO;
+    loop {
+        // the client may have pipelined several queries
+        let (response, keep_alive, consumed) = match HttpQuery::parse_next(&buf, limits) {
+            Ok(query) => {
+                let consumed = buf.len()-query.trailing.len();
+                let keep_alive = query.keep_alive() && !stop.load(Ordering::Acquire);
+                match panic::catch_unwind(AssertUnwindSafe(|| handler(query))) {
+                    Ok(response) => (response, keep_alive, consumed),
+                    // the handler may have left its state in a mess, don't give it another query
+                    Err(_) => (HttpResponse::internal_error(), false, consumed)
+                }
+            },
+            Err(ParserError::InvalidState(InvalidStateError::EOF)) if buf.len() > max_query => (HttpResponse::bad_request(), false, buf.len()),
+            Err(ParserError::InvalidState(InvalidStateError::EOF)) => {
+                let n = match stream.read(&mut chunk) {
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                        waited += POLL_INTERVAL;
+                        // a query that has started arriving gets the whole timeout, even when the
+                        // server is stopping
+                        let timeout = if buf.is_empty() { KEEP_ALIVE_TIMEOUT } else { READ_TIMEOUT };
+                        if waited >= timeout || (buf.is_empty() && stop.load(Ordering::Acquire)) {
+                            return Ok(());
+                        }
+                        continue;
+                    },
+                    Err(e) => return Err(e)
+                };
+                if n == 0 {
+                    // the client left, possibly before sending a whole query
+                    return Ok(());
+                }
+                waited = Duration::ZERO;
+                buf.extend_from_slice(&chunk[..n]);
+                continue;
+            },
+            // the rest of the query is still on its way, don't bother reading it
+            Err(ParserError::LimitExceeded(limit)) => (HttpResponse::limit_exceeded(limit), false, buf.len()),
+            Err(_) => (HttpResponse::bad_request(), false, buf.len())
+        };
+        buf.drain(..consumed);
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        stream.write_all(&response.with_header("Connection", connection).to_bytes())?;
+        stream.flush()?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
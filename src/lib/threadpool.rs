@@ -0,0 +1,70 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use crate::lib::messagequeue::{MessageQueueError, MessageQueueSender};
+
+/// A fixed set of threads running the same handler on the jobs they are given.
+/// The jobs wait in a single multi-consumer queue, from which the first free worker takes them.
+pub struct ThreadPool<J> {
+    /// Only None while the pool is being dropped
+    jobs: Option<MessageQueueSender<J>>,
+    workers: Vec<JoinHandle<()>>
+}
+
+impl<J: Send + 'static> ThreadPool<J> {
+    /// Spawn `workers` threads (at least one) running `handler` on every job, with room for
+    /// `backlog` jobs waiting for a free worker.
+    pub fn new<F>(workers: usize, backlog: usize, handler: F) -> Result<ThreadPool<J>, MessageQueueError>
+        where F: Fn(J) + Send + Sync + 'static {
+        if workers == 0 {
+            return Err(MessageQueueError::UnvalidSize);
+        }
+        let mut jobs = MessageQueueSender::new_spmc(backlog)?;
+        let reader = jobs.new_reader();
+        let handler = Arc::new(handler);
+        let workers = (0..workers).map(|_| {
+            let mut reader = reader.clone();
+            let handler = handler.clone();
+            thread::spawn(move || {
+                // the queue only closes once the pool is dropped and every job is taken
+                while let Some(job) = reader.blocking_read() {
+                    // a panicking job mustn't take the worker down with it, the pool would run
+                    // out of workers
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(job)));
+                }
+            })
+        }).collect();
+        Ok(ThreadPool {
+            jobs: Some(jobs),
+            workers
+        })
+    }
+
+    /// Hand `job` to the workers, waiting for room if the backlog is full.
+    /// Fails with `Disconnected` if every worker is gone, which shouldn't happen as the panics of
+    /// the handler are caught.
+    pub fn execute(&mut self, job: J) -> Result<(), MessageQueueError> {
+        self.jobs.as_mut().unwrap().blocking_send(job)
+    }
+
+    /// Number of worker threads
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Stop the pool: the workers finish the jobs already given to them, and this returns once
+    /// they are all done.
+    /// Dropping the pool does the same.
+    pub fn shutdown(self) {}
+}
+
+impl<J> Drop for ThreadPool<J> {
+    fn drop(&mut self) {
+        // the workers see the queue closing once they have taken the last jobs
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            // a worker whose handler panicked has nothing more to say
+            let _ = worker.join();
+        }
+    }
+}
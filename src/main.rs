@@ -1,9 +1,20 @@
-#![feature(test, exclusive_range_pattern)]
+#![feature(test)]
+#![allow(special_module_name)]
 extern crate test;
 extern crate libc;
 #[cfg(test)]
 mod tests;
 pub mod lib;
 
+use crate::lib::http::HttpResponse;
+use crate::lib::server;
+
 fn main() {
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+    println!("Listening on {}", addr);
+    let server = server::serve(&addr, |q| HttpResponse::ok(format!("Hello from {}\n", q.path).into_bytes()));
+    if let Err(e) = server.and_then(|server| server.join()) {
+        eprintln!("Couldn't serve on {}: {}", addr, e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,777 @@
+use crate::lib::aho::*;
+use std::collections::HashMap;
+use std::io;
+use rand::{Rng, RngCore};
+
+fn sample_tree() -> AhoTree<usize> {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"he", 0);
+    t.insert_rule(b"she", 1);
+    t.insert_rule(b"his", 2);
+    t.insert_rule(b"hers", 3);
+    t
+}
+
+#[test]
+fn insert_and_search() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"lol", 1);
+    t.insert_rule(b"lola", 2);
+    t.insert_rule(b"ola", 3);
+    assert_eq!(t.search(b"lol"), Ok(Some(1)));
+    assert_eq!(t.search(b"lola"), Ok(Some(2)));
+    assert_eq!(t.search(b"ola"), Ok(Some(3)));
+    assert_eq!(t.search(b"lo"), Ok(None));
+    assert_eq!(t.search(b"lolz"), Err(MatchingError::StringNotFound));
+
+    t.insert_rule(b"lol", 4);
+    assert_eq!(t.search(b"lol"), Ok(Some(4)));
+}
+
+#[test]
+#[allow(deprecated)]
+fn deprecated_alias() {
+    let mut t: aho_tree<usize> = aho_tree::new();
+    t.insert_rule(b"lol", 1);
+    assert_eq!(t.get(b"lol"), Some(1));
+}
+
+#[test]
+fn get() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"lol", 1);
+    t.insert_rule(b"lola", 2);
+    assert_eq!(t.get(b"lol"), Some(1));
+    assert_eq!(t.get(b"lola"), Some(2));
+    // both failure modes of search collapse into None
+    assert_eq!(t.get(b"lo"), None);
+    assert_eq!(t.get(b"lolz"), None);
+    assert_eq!(t.get(b""), None);
+    t.insert_rule(b"", 0);
+    assert_eq!(t.get(b""), Some(0));
+}
+
+#[test]
+fn non_clone_values() {
+    let mut t: AhoTree<Box<dyn Fn(usize) -> usize>> = AhoTree::new();
+    t.insert_rule(b"/double", Box::new(|x| x*2));
+    t.insert_rule(b"/square", Box::new(|x| x*x));
+    assert_eq!(t.len(), 2);
+    assert_eq!(t.get_ref(b"/double").map(|f| f(3)), Some(6));
+    assert_eq!(t.get_ref(b"/square").map(|f| f(3)), Some(9));
+    assert!(t.get_ref(b"/nope").is_none());
+
+    let (len, f) = t.longest_prefix_match_ref(b"/square/4").unwrap();
+    assert_eq!((len, f(4)), (7, 16));
+
+    assert_eq!(t.remove_rule(b"/double").map(|f| f(1)), Some(2));
+    assert!(t.get_ref(b"/double").is_none());
+}
+
+#[test]
+fn find_in_without_build() {
+    let t = sample_tree();
+    assert!(t.find_in(b"ushers", MatchMode::Overlapping).is_empty());
+}
+
+#[test]
+fn find_in() {
+    let mut t = sample_tree();
+    t.build();
+    assert_eq!(t.find_in(b"ushers", MatchMode::Overlapping), vec![(1, 1), (2, 0), (2, 3)]);
+    assert_eq!(t.find_in(b"ahishers", MatchMode::Overlapping), vec![(1, 2), (3, 1), (4, 0), (4, 3)]);
+    assert!(t.find_in(b"xyz", MatchMode::Overlapping).is_empty());
+    assert!(t.find_in(b"", MatchMode::Overlapping).is_empty());
+    // exact lookups still work on a built tree
+    assert_eq!(t.search(b"hers"), Ok(Some(3)));
+}
+
+#[test]
+fn from_rules() {
+    let t = AhoTree::from_rules(vec![(b"he".to_vec(), 0), (b"she".to_vec(), 1)]);
+    assert_eq!(t.get(b"she"), Some(1));
+    assert_eq!(t.find_in(b"ushe", MatchMode::Overlapping), vec![(1, 1), (2, 0)]);
+
+    let t: AhoTree<usize> = vec!["his", "hers"].into_iter()
+        .enumerate()
+        .map(|(i, k)| (k.as_bytes().to_vec(), i))
+        .collect();
+    assert_eq!(t.len(), 2);
+    assert_eq!(t.get(b"hers"), Some(1));
+    assert_eq!(t.find_in(b"hishers", MatchMode::Overlapping), vec![(0, 0), (3, 1)]);
+}
+
+#[test]
+fn find_in_leftmost_longest() {
+    let mut t = sample_tree();
+    t.build();
+    // she hides both he and hers
+    assert_eq!(t.find_in(b"ushers", MatchMode::LeftmostLongest), vec![(1, 1)]);
+    assert_eq!(t.find_in(b"hers", MatchMode::LeftmostLongest), vec![(0, 3)]);
+    assert_eq!(t.find_in(b"hishe", MatchMode::LeftmostLongest), vec![(0, 2), (3, 0)]);
+    assert_eq!(t.find_in(b"he he", MatchMode::LeftmostLongest), vec![(0, 0), (3, 0)]);
+
+    let mut t = AhoTree::new();
+    t.insert_rule(b"a", 0);
+    t.insert_rule(b"abc", 1);
+    t.insert_rule(b"bcd", 2);
+    t.build();
+    assert_eq!(t.find_in(b"abcd", MatchMode::Overlapping), vec![(0, 0), (0, 1), (1, 2)]);
+    assert_eq!(t.find_in(b"abcd", MatchMode::LeftmostLongest), vec![(0, 1)]);
+    assert_eq!(t.find_in(b"aabcd", MatchMode::LeftmostLongest), vec![(0, 0), (1, 1)]);
+}
+
+#[test]
+fn find_in_embedded() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"aab", 0);
+    t.insert_rule(b"ab", 1);
+    t.insert_rule(b"b", 2);
+    t.build();
+    assert_eq!(t.find_in(b"xaaab", MatchMode::Overlapping), vec![(2, 0), (3, 1), (4, 2)]);
+}
+
+#[test]
+fn count_matches() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"", 0);
+    t.insert_rule(b"a", 1);
+    t.insert_rule(b"aa", 2);
+    t.insert_rule(b"ab", 3);
+    t.insert_rule(b"abcd", 4);
+    assert!(t.count_matches(b"aaab").is_empty());
+    t.build();
+
+    let mut expected = HashMap::new();
+    expected.insert(b"a".to_vec(), 3);
+    expected.insert(b"aa".to_vec(), 2);
+    expected.insert(b"ab".to_vec(), 1);
+    assert_eq!(t.count_matches(b"aaab"), expected);
+
+    assert!(t.count_matches(b"").is_empty());
+    assert!(t.count_matches(b"xyz").is_empty());
+}
+
+#[test]
+fn scanner() {
+    let mut t = sample_tree();
+    t.build();
+    let mut scanner = t.scanner();
+    assert_eq!(scanner.push(b"us"), vec![]);
+    assert_eq!(scanner.push(b"h"), vec![]);
+    assert_eq!(scanner.push(b"ers "), vec![(1, 1), (2, 0), (2, 3)]);
+    assert_eq!(scanner.push(b""), vec![]);
+    assert_eq!(scanner.push(b"h"), vec![]);
+    assert_eq!(scanner.push(b"is"), vec![(7, 2)]);
+
+    // a pattern straddling the reset isn't found
+    scanner.push(b"s");
+    scanner.reset();
+    assert_eq!(scanner.push(b"he"), vec![(0, 0)]);
+}
+
+#[test]
+fn insert_invalidates_build() {
+    let mut t = sample_tree();
+    t.build();
+    t.insert_rule(b"us", 4);
+    assert!(t.find_in(b"ushers", MatchMode::Overlapping).is_empty());
+    t.build();
+    assert_eq!(t.find_in(b"ushers", MatchMode::Overlapping), vec![(0, 4), (1, 1), (2, 0), (2, 3)]);
+}
+
+#[test]
+fn empty_key() {
+    let mut t = AhoTree::new();
+    assert_eq!(t.search(b""), Ok(None));
+
+    t.insert_rule(b"a", 1);
+    t.insert_rule(b"", 0);
+    assert_eq!(t.search(b""), Ok(Some(0)));
+    assert_eq!(t.search(b"a"), Ok(Some(1)));
+    assert_eq!(t.search(b"b"), Err(MatchingError::StringNotFound));
+
+    // the empty key never matches inside a haystack
+    t.build();
+    assert_eq!(t.find_in(b"ab", MatchMode::Overlapping), vec![(0, 1)]);
+}
+
+#[test]
+fn remove_rule() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"", 0);
+    t.insert_rule(b"lol", 1);
+    t.insert_rule(b"lola", 2);
+    t.insert_rule(b"lolc", 3);
+
+    // removing a prefix of longer keys keeps them
+    assert_eq!(t.remove_rule(b"lol"), Some(1));
+    assert_eq!(t.search(b"lol"), Ok(None));
+    assert_eq!(t.search(b"lola"), Ok(Some(2)));
+    assert_eq!(t.remove_rule(b"lol"), None);
+
+    // non-existent keys
+    assert_eq!(t.remove_rule(b"lolz"), None);
+    assert_eq!(t.remove_rule(b"lo"), None);
+
+    // dead branches are pruned
+    assert_eq!(t.remove_rule(b"lola"), Some(2));
+    assert_eq!(t.remove_rule(b"lolc"), Some(3));
+    assert_eq!(t.search(b"l"), Err(MatchingError::StringNotFound));
+
+    // the empty key lives at the root
+    assert_eq!(t.remove_rule(b""), Some(0));
+    assert_eq!(t.search(b""), Ok(None));
+    assert_eq!(t.remove_rule(b""), None);
+}
+
+#[test]
+fn remove_rule_rebuild() {
+    let mut t = sample_tree();
+    t.build();
+    assert_eq!(t.remove_rule(b"he"), Some(0));
+    t.build();
+    assert_eq!(t.find_in(b"ushers", MatchMode::Overlapping), vec![(1, 1), (2, 3)]);
+}
+
+#[test]
+fn longest_prefix_match() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"/api", 1);
+    t.insert_rule(b"/api/v1", 2);
+    assert_eq!(t.longest_prefix_match(b"/api/v1/users"), Some((7, 2)));
+    assert_eq!(t.longest_prefix_match(b"/api/v1"), Some((7, 2)));
+    assert_eq!(t.longest_prefix_match(b"/api/v2"), Some((4, 1)));
+    assert_eq!(t.longest_prefix_match(b"/ap"), None);
+    assert_eq!(t.longest_prefix_match(b"/static"), None);
+    assert_eq!(t.longest_prefix_match(b""), None);
+
+    t.insert_rule(b"/", 0);
+    assert_eq!(t.longest_prefix_match(b"/static"), Some((1, 0)));
+    t.insert_rule(b"", 3);
+    assert_eq!(t.longest_prefix_match(b"static"), Some((0, 3)));
+}
+
+#[test]
+fn search_prefix() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"/", 0);
+    t.insert_rule(b"/api/", 1);
+    t.insert_rule(b"/api/users", 2);
+    t.insert_rule(b"/api/groups", 3);
+    t.insert_rule(b"/static", 4);
+
+    let mut res = t.search_prefix(b"/api/");
+    res.sort();
+    assert_eq!(res, vec![(b"/api/".to_vec(), 1), (b"/api/groups".to_vec(), 3), (b"/api/users".to_vec(), 2)]);
+
+    assert_eq!(t.search_prefix(b"/api/u"), vec![(b"/api/users".to_vec(), 2)]);
+    assert_eq!(t.search_prefix(b"/static"), vec![(b"/static".to_vec(), 4)]);
+    assert!(t.search_prefix(b"/nope").is_empty());
+    assert_eq!(t.search_prefix(b"").len(), 5);
+}
+
+#[test]
+fn prefix_count() {
+    let mut t = AhoTree::new();
+    for (i, key) in [&b"car"[..], b"cart", b"carton", b"care", b"cat", b"dog"].iter().enumerate() {
+        t.insert_rule(key, i);
+    }
+    assert_eq!(t.prefix_count(b""), 6);
+    assert_eq!(t.prefix_count(b"ca"), 5);
+    assert_eq!(t.prefix_count(b"car"), 4);
+    assert_eq!(t.prefix_count(b"cart"), 2);
+    assert_eq!(t.prefix_count(b"carton"), 1);
+    assert_eq!(t.prefix_count(b"cartons"), 0);
+    assert_eq!(t.prefix_count(b"x"), 0);
+
+    // the counts follow removals
+    t.remove_rule(b"cart");
+    assert_eq!(t.prefix_count(b"cart"), 1);
+    t.remove_rule(b"carton");
+    assert_eq!(t.prefix_count(b"cart"), 0);
+    assert_eq!(t.prefix_count(b"car"), 2);
+
+    let mut t = AhoTree::new_ascii_ci();
+    t.insert_rule(b"Hello", 0);
+    t.insert_rule(b"help", 1);
+    assert_eq!(t.prefix_count(b"HEL"), 2);
+}
+
+#[test]
+fn update() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"lol", 1);
+    t.update(b"lol", |x| x.map(|x| x+10));
+    assert_eq!(t.get(b"lol"), Some(11));
+
+    // missing keys are created
+    t.update(b"lola", |x| {
+        assert_eq!(x, None);
+        Some(2)
+    });
+    assert_eq!(t.get(b"lola"), Some(2));
+    assert_eq!(t.len(), 2);
+
+    // returning None clears the value
+    t.update(b"lola", |_| None);
+    assert_eq!(t.search(b"lola"), Err(MatchingError::StringNotFound));
+    t.update(b"lol", |_| None);
+    assert_eq!(t.get(b"lol"), None);
+    assert!(t.is_empty());
+
+    // no dead branch is left behind
+    t.update(b"abc", |_| None);
+    assert_eq!(t.search(b"a"), Err(MatchingError::StringNotFound));
+}
+
+#[test]
+fn merge() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"lol", 1);
+    t.insert_rule(b"lola", 2);
+    let mut other = AhoTree::new();
+    other.insert_rule(b"lol", 3);
+    other.insert_rule(b"ab", 4);
+    // an intermediate node of other must not clear the value of t
+    other.insert_rule(b"lolaz", 5);
+
+    t.merge(other);
+    assert_eq!(t.get(b"lol"), Some(3));
+    assert_eq!(t.get(b"lola"), Some(2));
+    assert_eq!(t.get(b"ab"), Some(4));
+    assert_eq!(t.get(b"lolaz"), Some(5));
+    assert_eq!(t.len(), 4);
+
+    let mut ci = AhoTree::new_ascii_ci();
+    let mut other = AhoTree::new();
+    other.insert_rule(b"Host", 0);
+    ci.merge(other);
+    assert_eq!(ci.get(b"host"), Some(0));
+}
+
+#[test]
+fn len() {
+    let mut t = AhoTree::new();
+    assert_eq!(t.len(), 0);
+    assert!(t.is_empty());
+
+    t.insert_rule(b"lol", 1);
+    t.insert_rule(b"lola", 2);
+    t.insert_rule(b"", 3);
+    assert_eq!(t.len(), 3);
+    assert!(!t.is_empty());
+
+    // overwriting doesn't add a key
+    t.insert_rule(b"lol", 4);
+    assert_eq!(t.len(), 3);
+
+    t.remove_rule(b"lol");
+    t.remove_rule(b"nope");
+    assert_eq!(t.len(), 2);
+    t.remove_rule(b"lola");
+    t.remove_rule(b"");
+    assert!(t.is_empty());
+}
+
+#[test]
+fn unordered_insertions() {
+    let mut t = AhoTree::new();
+    for (i, c) in b"zaymbx".iter().enumerate() {
+        t.insert_rule(&[*c], i);
+    }
+    for (i, c) in b"zaymbx".iter().enumerate() {
+        assert_eq!(t.search(&[*c]), Ok(Some(i)));
+    }
+    assert_eq!(t.search_prefix(b"").iter().map(|x| x.0[0]).collect::<Vec<u8>>(), b"abmxyz".to_vec());
+}
+
+#[test]
+fn iter() {
+    let mut t = AhoTree::new();
+    assert_eq!(t.iter().next(), None);
+
+    t.insert_rule(b"lolc", 3);
+    t.insert_rule(b"lol", 1);
+    t.insert_rule(b"lola", 2);
+    t.insert_rule(b"ab", 0);
+    assert_eq!(t.iter().collect::<Vec<_>>(), vec![
+        (b"ab".to_vec(), 0),
+        (b"lol".to_vec(), 1),
+        (b"lola".to_vec(), 2),
+        (b"lolc".to_vec(), 3)
+    ]);
+
+    t.insert_rule(b"", 4);
+    let mut it = t.iter();
+    assert_eq!(it.next(), Some((b"".to_vec(), 4)));
+    assert_eq!(it.count(), 4);
+    assert_eq!((&t).into_iter().count(), t.len());
+}
+
+#[test]
+fn case_insensitive() {
+    let mut t = AhoTree::new_ascii_ci();
+    t.insert_rule(b"Content-Type", 0);
+    t.insert_rule(b"\xC9t\xE9", 1);
+    assert_eq!(t.search(b"content-type"), Ok(Some(0)));
+    assert_eq!(t.search(b"CONTENT-TYPE"), Ok(Some(0)));
+    assert_eq!(t.search(b"Content-Type"), Ok(Some(0)));
+    // non-ASCII bytes are left untouched
+    assert_eq!(t.search(b"\xC9T\xE9"), Ok(Some(1)));
+    assert_eq!(t.search(b"\xE9t\xE9"), Err(MatchingError::StringNotFound));
+    assert_eq!(t.search_prefix(b"CONTENT"), vec![(b"content-type".to_vec(), 0)]);
+
+    t.build();
+    assert_eq!(t.find_in(b"x-CoNtEnT-tYpE", MatchMode::Overlapping), vec![(2, 0)]);
+
+    assert_eq!(t.remove_rule(b"CONTENT-type"), Some(0));
+    assert!(t.search(b"content-type").is_err());
+
+    // the default mode is case-sensitive
+    let mut t = AhoTree::new();
+    t.insert_rule(b"Host", 0);
+    assert!(t.search(b"host").is_err());
+    let mut t = AhoTree::new();
+    t.set_case_insensitive(true);
+    t.insert_rule(b"Host", 0);
+    assert_eq!(t.search(b"host"), Ok(Some(0)));
+}
+
+#[test]
+fn serialize() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"", String::from("root"));
+    t.insert_rule(b"lol", String::from("a"));
+    t.insert_rule(b"lola", String::from("b"));
+    t.insert_rule(b"ab", String::from("c"));
+
+    let bytes = t.serialize();
+    let mut t2 = AhoTree::<String>::deserialize(&bytes).unwrap();
+    for key in [&b""[..], b"lol", b"lola", b"ab"].iter() {
+        assert_eq!(t2.search(key), t.search(key));
+    }
+    // intermediate nodes are kept
+    assert_eq!(t2.search(b"lo"), Ok(None));
+    assert_eq!(t2.search(b"x"), Err(MatchingError::StringNotFound));
+    assert_eq!(t2.len(), 4);
+    assert_eq!(t2.serialize(), bytes);
+
+    t2.build();
+    assert_eq!(t2.find_in(b"xlola", MatchMode::Overlapping), vec![(1, String::from("a")), (1, String::from("b"))]);
+
+    let mut t = AhoTree::<u32>::new_ascii_ci();
+    t.insert_rule(b"Host", 42);
+    let t2 = AhoTree::<u32>::deserialize(&t.serialize()).unwrap();
+    assert_eq!(t2.get(b"HOST"), Some(42));
+}
+
+#[test]
+fn deserialize_invalid() {
+    let mut t = AhoTree::<u64>::new();
+    t.insert_rule(b"lol", 1);
+    let bytes = t.serialize();
+
+    assert_eq!(AhoTree::<u64>::deserialize(b"").err(), Some(MatchingError::InvalidSerialization));
+    assert_eq!(AhoTree::<u64>::deserialize(b"nope\x00").err(), Some(MatchingError::InvalidSerialization));
+    for len in 0..bytes.len() {
+        assert_eq!(AhoTree::<u64>::deserialize(&bytes[..len]).err(), Some(MatchingError::InvalidSerialization));
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(AhoTree::<u64>::deserialize(&trailing).err(), Some(MatchingError::InvalidSerialization));
+}
+
+#[test]
+fn deserialize_deep() {
+    // a root and `depth` nested nodes, the last one holding a value
+    let nested = |depth: usize| {
+        let mut bytes = b"AHO\x01\x00\x00\x00\x01\x00\x00\x00".to_vec();
+        for _ in 1..depth {
+            bytes.extend_from_slice(b"a\x00\x01\x00\x00\x00");
+        }
+        bytes.extend_from_slice(b"a\x01\x2a\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        bytes
+    };
+    let t = AhoTree::<u64>::deserialize(&nested(1024)).unwrap();
+    assert_eq!(t.get(&[b'a'; 1024]), Some(42));
+    assert_eq!(AhoTree::<u64>::deserialize(&t.serialize()).unwrap().len(), 1);
+
+    // this would overflow the stack if the depth wasn't bounded
+    assert_eq!(AhoTree::<u64>::deserialize(&nested(1025)).err(), Some(MatchingError::InvalidSerialization));
+    assert_eq!(AhoTree::<u64>::deserialize(&nested(1_000_000)).err(), Some(MatchingError::InvalidSerialization));
+}
+
+#[test]
+fn node_count_and_height() {
+    let mut t = AhoTree::new();
+    assert_eq!((t.node_count(), t.height()), (1, 0));
+
+    // root -> l -> o -> l -> {a, c}, root -> a -> b
+    t.insert_rule(b"lola", 0);
+    t.insert_rule(b"lolc", 1);
+    t.insert_rule(b"ab", 2);
+    assert_eq!(t.node_count(), 8);
+    assert_eq!(t.height(), 4);
+
+    t.insert_rule(b"lol", 3);
+    assert_eq!(t.node_count(), 8);
+    t.remove_rule(b"lola");
+    t.remove_rule(b"lolc");
+    assert_eq!((t.node_count(), t.height()), (6, 3));
+}
+
+#[test]
+fn packed() {
+    let mut t = sample_tree();
+    t.insert_rule(b"", 4);
+    t.insert_rule(b"\xFF\x00", 5);
+    let packed = t.clone().into_packed();
+    for key in [&b""[..], b"he", b"she", b"his", b"hers", b"h", b"hersx", b"x", b"\xFF\x00"].iter() {
+        assert_eq!(packed.get(key), t.get(key));
+    }
+
+    t.build();
+    for haystack in [&b"ushers"[..], b"ahishers", b"", b"hhhe\xFF\x00he"].iter() {
+        for mode in [MatchMode::Overlapping, MatchMode::LeftmostLongest].iter() {
+            assert_eq!(packed.find_in(haystack, *mode), t.find_in(haystack, *mode));
+        }
+    }
+
+    let mut t = AhoTree::new_ascii_ci();
+    t.insert_rule(b"Host", 0);
+    let packed = t.into_packed();
+    assert_eq!(packed.get(b"HOST"), Some(0));
+    assert_eq!(packed.find_in(b"xhOsT", MatchMode::Overlapping), vec![(1, 0)]);
+}
+
+#[test]
+fn packed_random() {
+    let keys = generate_keys(2000);
+    let t: AhoTree<usize> = keys.iter().cloned().zip(0..).collect();
+    let packed = t.clone().into_packed();
+    for k in &keys {
+        assert_eq!(packed.get(k), t.get(k));
+    }
+    let haystack: Vec<u8> = keys[..50].concat();
+    assert_eq!(packed.find_in(&haystack, MatchMode::Overlapping), t.find_in(&haystack, MatchMode::Overlapping));
+}
+
+#[test]
+fn matching_error_to_io_error() {
+    fn lookup(t: &AhoTree<usize>, key: &[u8]) -> io::Result<Option<usize>> {
+        Ok(t.search(key)?)
+    }
+
+    let t = AhoTree::new();
+    let err = lookup(&t, b"lol").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert!(err.to_string().contains("pattern"));
+
+    let err: io::Error = AhoTree::<usize>::deserialize(b"").unwrap_err().into();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), MatchingError::InvalidSerialization.to_string());
+}
+
+#[test]
+fn glob() {
+    let mut t = AhoTree::new();
+    t.insert_glob(b"/user/?", 1);
+    t.insert_glob(b"/files/*", 2);
+    t.insert_glob(b"/files/*/raw", 3);
+    t.insert_glob(b"/lit/\\*", 4);
+    t.insert_rule(b"/user/me", 5);
+
+    assert_eq!(t.search(b"/user/a"), Ok(Some(1)));
+    // a path to the literal key, but no value
+    assert_eq!(t.search(b"/user/"), Ok(None));
+    assert!(t.search(b"/user/ab").is_err());
+    // the literal keys come first
+    assert_eq!(t.search(b"/user/me"), Ok(Some(5)));
+    assert_eq!(t.search(b"/user/m"), Ok(Some(1)));
+
+    // `*` doesn't cross a '/'
+    assert_eq!(t.search(b"/files/"), Ok(Some(2)));
+    assert_eq!(t.search(b"/files/a.txt"), Ok(Some(2)));
+    assert_eq!(t.search(b"/files/a.txt/raw"), Ok(Some(3)));
+    assert_eq!(t.search(b"/files//raw"), Ok(Some(3)));
+    assert!(t.search(b"/files/a/b").is_err());
+
+    // escaped wildcards are literal
+    assert_eq!(t.search(b"/lit/*"), Ok(Some(4)));
+    assert!(t.search(b"/lit/x").is_err());
+
+    // only search sees the wildcards, a pattern without any being a plain key
+    assert_eq!(t.len(), 2);
+    assert_eq!(t.get(b"/lit/*"), Some(4));
+    assert_eq!(t.get(b"/user/a"), None);
+    assert_eq!(t.remove_rule(b"/user/me"), Some(5));
+    assert_eq!(t.search(b"/user/m"), Ok(Some(1)));
+    assert!(t.search(b"/user/me").is_err());
+}
+
+#[test]
+fn glob_invalidates_automaton() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"he", 1);
+    t.build();
+    assert_eq!(t.find_first(b"she"), Some((1, 1)));
+
+    // an empty pattern stores a value at the root, the automaton doesn't know about it
+    t.insert_glob(b"", 2);
+    assert!(t.find_in(b"she", MatchMode::Overlapping).is_empty());
+    assert_eq!(t.find_first(b"she"), None);
+    t.build();
+    assert_eq!(t.find_first(b"she"), Some((1, 1)));
+    assert_eq!(t.get(b""), Some(2));
+}
+
+#[test]
+fn insert_returns_previous() {
+    let mut t = AhoTree::new();
+    assert_eq!(t.insert_rule(b"lol", 1), None);
+    assert_eq!(t.insert_rule(b"lola", 2), None);
+    assert_eq!(t.insert_rule(b"lol", 3), Some(1));
+    assert_eq!(t.insert_rule(b"", 4), None);
+    assert_eq!(t.insert_rule(b"", 5), Some(4));
+    assert_eq!(t.len(), 3);
+    assert_eq!(t.get(b"lol"), Some(3));
+
+    let mut t = AhoTree::new_ascii_ci();
+    assert_eq!(t.insert_rule(b"Lol", 1), None);
+    assert_eq!(t.insert_rule(b"LOL", 2), Some(1));
+}
+
+#[test]
+fn insert_many() {
+    let mut t = AhoTree::new();
+    t.insert_rule(b"lol", 0);
+    let conflicts = t.insert_many(vec![(b"lola".to_vec(), 1), (b"lol".to_vec(), 2), (b"ola".to_vec(), 3)]);
+    assert_eq!(conflicts, vec![b"lol".to_vec()]);
+    assert_eq!(t.len(), 3);
+    assert_eq!(t.get(b"lol"), Some(2));
+
+    // conflicts within the rules themselves, the empty key included
+    let conflicts = t.insert_many(vec![(Vec::new(), 4), (b"new".to_vec(), 5), (Vec::new(), 6)]);
+    assert_eq!(conflicts, vec![Vec::new()]);
+    assert_eq!(t.get(b""), Some(6));
+    assert!(t.insert_many(Vec::new()).is_empty());
+
+    // the keys are reported as given, not folded
+    let mut t = AhoTree::new_ascii_ci();
+    assert_eq!(t.insert_many(vec![(b"Key".to_vec(), 1), (b"KEY".to_vec(), 2)]), vec![b"KEY".to_vec()]);
+}
+
+#[test]
+fn find_first() {
+    let mut t = sample_tree();
+    assert_eq!(t.find_first(b"ushers"), None);
+    t.build();
+    // she and he both end at 3, the longest one wins
+    assert_eq!(t.find_first(b"ushers"), Some((1, 1)));
+    assert_eq!(t.find_first(b"this is hers"), Some((1, 2)));
+    assert_eq!(t.find_first(b"nothing"), None);
+    assert_eq!(t.find_first(b""), None);
+
+    // the scan stops at the first match: a match ending later doesn't count, even if it starts
+    // earlier
+    let mut t = AhoTree::new();
+    t.insert_rule(b"abcd", 0);
+    t.insert_rule(b"bc", 1);
+    t.build();
+    assert_eq!(t.find_first(b"xabcd"), Some((2, 1)));
+    assert_eq!(t.find_in(b"xabcd", MatchMode::LeftmostLongest), vec![(1, 0)]);
+
+    let mut t = AhoTree::new_ascii_ci();
+    t.insert_rule(b"Evil", 0);
+    t.build();
+    assert_eq!(t.find_first(b"an EVIL payload, evil again"), Some((3, 0)));
+}
+
+// generate num random keys
+fn generate_keys(num: usize) -> Vec<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    let mut keys = Vec::with_capacity(num);
+    for _ in 0..num {
+        let mut key = vec![0; rng.gen_range(1, 16)];
+        rng.fill_bytes(&mut key);
+        keys.push(key);
+    }
+    keys
+}
+
+#[bench]
+fn insert_10k_keys(b: &mut test::Bencher) {
+    let keys = generate_keys(10000);
+    b.iter(|| {
+        let mut t = AhoTree::new();
+        for (i, k) in keys.iter().enumerate() {
+            t.insert_rule(k, i);
+        }
+    });
+}
+
+#[bench]
+fn insert_10k_keys_preallocated(b: &mut test::Bencher) {
+    let keys = generate_keys(10000);
+    b.iter(|| {
+        let mut t = AhoTree::with_child_capacity(256);
+        for (i, k) in keys.iter().enumerate() {
+            t.insert_rule(k, i);
+        }
+    });
+}
+
+#[bench]
+fn search_10k_keys(b: &mut test::Bencher) {
+    let keys = generate_keys(10000);
+    let mut t = AhoTree::new();
+    for (i, k) in keys.iter().enumerate() {
+        t.insert_rule(k, i);
+    }
+    b.iter(|| {
+        for k in &keys {
+            assert!(t.search(k).unwrap().is_some());
+        }
+    });
+}
+
+#[bench]
+fn search_50k_keys_tree(b: &mut test::Bencher) {
+    let keys = generate_keys(50000);
+    let t: AhoTree<usize> = keys.iter().cloned().zip(0..).collect();
+    b.iter(|| {
+        for k in &keys {
+            assert!(t.get_ref(k).is_some());
+        }
+    });
+}
+
+#[bench]
+fn search_50k_keys_packed(b: &mut test::Bencher) {
+    let keys = generate_keys(50000);
+    let t: AhoTree<usize> = keys.iter().cloned().zip(0..).collect();
+    let packed = t.into_packed();
+    b.iter(|| {
+        for k in &keys {
+            assert!(packed.get_ref(k).is_some());
+        }
+    });
+}
+
+#[bench]
+fn find_in_50k_keys_tree(b: &mut test::Bencher) {
+    let keys = generate_keys(50000);
+    let haystack = generate_keys(1000).concat();
+    let t: AhoTree<usize> = keys.iter().cloned().zip(0..).collect();
+    b.iter(|| t.find_in(&haystack, MatchMode::Overlapping));
+}
+
+#[bench]
+fn find_in_50k_keys_packed(b: &mut test::Bencher) {
+    let keys = generate_keys(50000);
+    let haystack = generate_keys(1000).concat();
+    let t: AhoTree<usize> = keys.iter().cloned().zip(0..).collect();
+    let packed = t.into_packed();
+    b.iter(|| packed.find_in(&haystack, MatchMode::Overlapping));
+}
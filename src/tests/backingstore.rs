@@ -0,0 +1,126 @@
+use crate::lib::backingstore::*;
+use std::env;
+use std::fs;
+use std::process;
+use std::thread;
+
+#[test]
+fn file_backed() {
+    let path = env::temp_dir().join(format!("webserv_backingstore_{}", process::id()));
+    {
+        // any bytes make a valid u64
+        let store = unsafe { BackingStore::<u64>::new_file(&path, 512) }.unwrap();
+        for i in 0..512 {
+            store.try_set(i, i as u64 * 3).unwrap();
+        }
+    }
+    assert_eq!(fs::metadata(&path).unwrap().len(), 512*8);
+
+    // the content survives the mapping
+    let store = unsafe { BackingStore::<u64>::new_file(&path, 512) }.unwrap();
+    for i in 0..512 {
+        assert_eq!(unsafe { store.get(i) }, i as u64 * 3);
+    }
+    drop(store);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bounds_checks() {
+    let store = BackingStore::<usize>::new(16).unwrap();
+    assert_eq!(store.try_set(15, 42), Ok(()));
+    assert_eq!(store.try_set(16, 43), Err(OutOfBounds {}));
+    assert_eq!(store.try_set(usize::MAX, 44), Err(OutOfBounds {}));
+    // only slot 15 was set
+    unsafe {
+        assert_eq!(store.try_get(15), Some(42));
+        assert_eq!(store.try_get(16), None);
+    }
+}
+
+#[test]
+fn resize() {
+    let mut store = BackingStore::<usize>::new(16).unwrap();
+    for i in 0..16 {
+        store.try_set(i, i).unwrap();
+    }
+    store.resize(1 << 20).unwrap();
+    store.try_set((1 << 20) - 1, 42).unwrap();
+    unsafe {
+        for i in 0..16 {
+            assert_eq!(store.get(i), i);
+        }
+        assert_eq!(store.try_get(1 << 20), None);
+        assert_eq!(store.get((1 << 20) - 1), 42);
+    }
+
+    let path = env::temp_dir().join(format!("webserv_backingstore_resize_{}", process::id()));
+    let mut store = unsafe { BackingStore::<u8>::new_file(&path, 16) }.unwrap();
+    assert!(store.resize(32).is_err());
+    drop(store);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "slot 3 was read before being set")]
+fn read_uninitialized() {
+    let store = BackingStore::<String>::new(16).unwrap();
+    store.try_set(2, "set".into()).unwrap();
+    // the mistake debug builds catch
+    unsafe { store.get(3) };
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "slot 2 was read before being set")]
+fn read_twice() {
+    let store = BackingStore::<String>::new(16).unwrap();
+    store.try_set(2, "set".into()).unwrap();
+    let _first = unsafe { store.get(2) };
+    let _second = unsafe { store.get(2) };
+}
+
+#[test]
+fn advise() {
+    let store = BackingStore::<u64>::new(1 << 20).unwrap();
+    store.advise_sequential();
+    store.advise_willneed();
+    for i in 0..1 << 20 {
+        store.try_set(i, i as u64).unwrap();
+    }
+    assert_eq!(unsafe { store.get((1 << 20) - 1) }, (1 << 20) - 1);
+}
+
+#[test]
+fn numa_node() {
+    // node 0 exists even without NUMA, and the others are merely a hint
+    for &node in [0, 1 << 20, -1].iter() {
+        let store = BackingStore::<u64>::new_on_node(1 << 16, node).unwrap();
+        for i in 0..1 << 16 {
+            store.try_set(i, i as u64).unwrap();
+        }
+        assert_eq!(unsafe { store.get((1 << 16) - 1) }, (1 << 16) - 1);
+    }
+}
+
+#[test]
+fn owned_elements() {
+    // the strings are moved in and out of the store, each one must be freed exactly once
+    let store = BackingStore::<String>::new(16).unwrap();
+    for i in 0..16 {
+        store.try_set(i, i.to_string()).unwrap();
+    }
+    for i in 0..8 {
+        assert_eq!(unsafe { store.get(i) }, i.to_string());
+        store.try_set(i, format!("again {}", i)).unwrap();
+    }
+    // the store can move to another thread along with its elements
+    let store = thread::spawn(move || {
+        assert_eq!(unsafe { store.get(15) }, "15");
+        store
+    }).join().unwrap();
+    for i in 0..15 {
+        drop(unsafe { store.get(i) });
+    }
+}
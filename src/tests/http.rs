@@ -1,9 +1,363 @@
 use test::Bencher;
 use std::str;
+use std::thread;
 use crate::lib::http;
+use crate::lib::parser::{InvalidStateError, Limit, ParserError};
 use rand::{Rng, RngCore};
 
-static BASE_QUERY: &'static str = "\r\n\r\nGET /lol17 HTTP/1.1\r\ntype: lol\r\n\r\n";
+static BASE_QUERY: &str = "\r\n\r\nGET /lol17 HTTP/1.1\r\ntype: lol\r\n\r\n";
+
+#[test]
+fn parse_query() {
+    let req = format!("{}Hi, what's up ?", BASE_QUERY);
+    let query = http::HttpQuery::from_string(req.as_bytes()).unwrap();
+    assert_eq!(query.verb, http::HTTPVerb::GET);
+    assert_eq!(query.url, "/lol17");
+    assert_eq!(query.headers.len(), 1);
+    assert!(query.header("type").is_some());
+    assert_eq!(*query.body, *b"Hi, what's up ?");
+
+    assert!(http::HttpQuery::from_string(b"GET /lol17 HTTP/1.1\r\nbroken\r\n\r\n").is_err());
+    assert!(http::HttpQuery::from_string(b"GET /lol17 HTTP/1.1\r\ntype: lol\r\n").is_err());
+}
+
+#[test]
+fn query_params() {
+    let query = http::HttpQuery::from_string(b"GET /search?q=hello%20world&n=2&lang=fr+en HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.url, "/search?q=hello%20world&n=2&lang=fr+en");
+    assert_eq!(query.path, "/search");
+    assert_eq!(query.query, Some("q=hello%20world&n=2&lang=fr+en"));
+    let params = query.query_params();
+    assert_eq!(params.len(), 3);
+    assert_eq!(params["q"], "hello world");
+    assert_eq!(params["n"], "2");
+    assert_eq!(params["lang"], "fr en");
+
+    let query = http::HttpQuery::from_string(b"GET /search?a%26b=1&flag&empty=&a%26b=2&&bad=%zz HTTP/1.1\r\n\r\n").unwrap();
+    let params = query.query_params();
+    assert_eq!(params.len(), 4);
+    // last one wins
+    assert_eq!(params["a&b"], "2");
+    assert_eq!(params["flag"], "");
+    assert_eq!(params["empty"], "");
+    assert_eq!(params["bad"], "%zz");
+
+    let query = http::HttpQuery::from_string(b"GET /search HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.path, "/search");
+    assert_eq!(query.query, None);
+    assert!(query.query_params().is_empty());
+    let query = http::HttpQuery::from_string_owned(b"GET /search?x=1?2 HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.query.as_deref(), Some("x=1?2"));
+    assert_eq!(query.query_params()["x"], "1?2");
+}
+
+#[test]
+fn decoded_path() {
+    let decode = |req: &[u8]| http::HttpQuery::from_string(req).unwrap().decoded_path();
+    let query = http::HttpQuery::from_string(b"GET /files/a%20b%2Fc+d?x=%20 HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.decoded_path().unwrap(), "/files/a b/c+d");
+    assert_eq!(query.url, "/files/a%20b%2Fc+d?x=%20");
+    assert_eq!(decode(b"GET /caf%C3%a9 HTTP/1.1\r\n\r\n").unwrap(), "/caf\u{e9}");
+    assert!(matches!(decode(b"GET /a%2 HTTP/1.1\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(decode(b"GET /a% HTTP/1.1\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(decode(b"GET /a%G0 HTTP/1.1\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(decode(b"GET /a%ff HTTP/1.1\r\n\r\n"), Err(ParserError::UTFError(_))));
+}
+
+#[test]
+fn typed_body() {
+    let query = http::HttpQuery::from_string(b"POST /a HTTP/1.1\r\nContent-Length: 6\r\n\r\ncaf\xc3\xa9!").unwrap();
+    assert_eq!(query.body_str().unwrap(), "caf\u{e9}!");
+    // no form without the right Content-Type
+    assert!(query.form_params().is_empty());
+    let query = http::HttpQuery::from_string(b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\na\xffb").unwrap();
+    assert!(matches!(query.body_str(), Err(ParserError::UTFError(_))));
+    let query = http::HttpQuery::from_string(b"POST /a HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.body_str().unwrap(), "");
+
+    let req = b"POST /login HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded; charset=utf-8\r\nContent-Length: 35\r\n\r\nuser=jean+dupont&pass=a%26b%3D&keep";
+    let query = http::HttpQuery::from_string(req).unwrap();
+    let params = query.form_params();
+    assert_eq!(params.len(), 3);
+    assert_eq!(params["user"], "jean dupont");
+    assert_eq!(params["pass"], "a&b=");
+    assert_eq!(params["keep"], "");
+    assert_eq!(http::OwnedHttpQuery::from(&query).form_params(), params);
+}
+
+#[test]
+fn content_length() {
+    let req = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\nContent-length: 0\r\n\r\n";
+    let first = http::HttpQuery::from_string(req).unwrap();
+    assert_eq!(first.url, "/a");
+    assert_eq!(*first.body, *b"hello");
+    let second = http::HttpQuery::from_string(first.trailing).unwrap();
+    assert_eq!(second.url, "/b");
+    assert_eq!(*second.body, *b"");
+    assert_eq!(second.trailing, b"");
+
+    // without Content-Length, everything is the body
+    let query = http::HttpQuery::from_string(b"POST /a HTTP/1.1\r\n\r\nhello").unwrap();
+    assert_eq!(*query.body, *b"hello");
+    assert_eq!(query.trailing, b"");
+
+    assert!(matches!(http::HttpQuery::from_string(b"POST /a HTTP/1.1\r\nContent-Length: 6\r\n\r\nhello"),
+        Err(ParserError::InvalidState(InvalidStateError::EOF))));
+    assert!(matches!(http::HttpQuery::from_string(b"POST /a HTTP/1.1\r\nContent-Length: -1\r\n\r\nhello"),
+        Err(ParserError::InvalidData)));
+
+    // repeated lengths must agree, or the body of a query could hide another one
+    let query = http::HttpQuery::from_string(b"POST /a HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\nhello").unwrap();
+    assert_eq!(*query.body, *b"hello");
+    let req = b"POST / HTTP/1.1\r\nContent-Length: 0\r\nContent-Length: 23\r\n\r\nGET /admin HTTP/1.1\r\n\r\n";
+    assert!(matches!(http::HttpQuery::parse_all(req), Err(ParserError::InvalidData)));
+    assert!(matches!(http::HttpQuery::from_string(b"POST /a HTTP/1.1\r\nContent-Length: 5, 0\r\n\r\nhello"),
+        Err(ParserError::InvalidData)));
+}
+
+#[test]
+fn chunked_body() {
+    let req = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\nB;ext=1\r\n, chunked !\r\n0\r\nExpires: never\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+    let query = http::HttpQuery::from_string(req).unwrap();
+    assert_eq!(*query.body, *b"hello, chunked !");
+    assert_eq!(query.trailing, b"GET /b HTTP/1.1\r\n\r\n");
+
+    let parse = |body: &[u8]| {
+        let mut req = b"POST /a HTTP/1.1\r\ntransfer-encoding: Chunked\r\n\r\n".to_vec();
+        req.extend_from_slice(body);
+        http::HttpQuery::from_string(&req).map(|query| query.body.to_vec())
+    };
+    assert_eq!(parse(b"0\r\n\r\n").unwrap(), b"");
+    assert!(matches!(parse(b"5x\r\nhello\r\n0\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(parse(b"\r\nhello\r\n0\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(parse(b"5\r\nhello!\r\n0\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(parse(b"5\r\nhel"), Err(ParserError::InvalidState(InvalidStateError::EOF))));
+    assert!(matches!(parse(b"5\r\nhello\r\n0\r\n"), Err(ParserError::InvalidState(InvalidStateError::EOF))));
+
+    let parse = |headers: &[u8]| {
+        let mut req = b"POST /a HTTP/1.1\r\n".to_vec();
+        req.extend_from_slice(headers);
+        req.extend_from_slice(b"\r\n5\r\nhello\r\n0\r\n\r\n");
+        http::HttpQuery::from_string(&req).map(|query| query.body.to_vec())
+    };
+    assert_eq!(parse(b"Transfer-Encoding: gzip, chunked\r\n").unwrap(), b"hello");
+    assert_eq!(parse(b"Transfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n").unwrap(), b"hello");
+    assert!(matches!(parse(b"Transfer-Encoding: gzip\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(parse(b"Transfer-Encoding: chunked, gzip\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(parse(b"Transfer-Encoding: chunked\r\nContent-Length: 10\r\n"), Err(ParserError::InvalidData)));
+}
+
+#[test]
+fn header_lookup() {
+    let req = b"GET / HTTP/1.1\r\nContent-Type:text/plain\r\nX-Forwarded-For:10.0.0.1\r\nx-forwarded-for:10.0.0.2\r\n\r\n";
+    let query = http::HttpQuery::from_string(req).unwrap();
+    assert_eq!(query.header("content-type"), Some("text/plain"));
+    assert_eq!(query.header("Content-Type"), Some("text/plain"));
+    assert_eq!(query.header("CONTENT-TYPE"), Some("text/plain"));
+    assert_eq!(query.header("Content-Length"), None);
+    assert_eq!(query.header("X-Forwarded-For"), Some("10.0.0.1"));
+    assert_eq!(query.headers_all("X-Forwarded-For"), vec!["10.0.0.1", "10.0.0.2"]);
+    assert!(query.headers_all("Host").is_empty());
+    assert_eq!(query.headers.len(), 3);
+
+    let query = http::HttpQuery::from_string_owned(req).unwrap();
+    assert_eq!(query.header("content-type"), Some("text/plain"));
+    assert_eq!(query.headers_all("x-FORWARDED-for"), vec!["10.0.0.1", "10.0.0.2"]);
+}
+
+#[test]
+fn headers_in_order() {
+    let req = b"GET / HTTP/1.1\r\nZ: 1\r\nA: 2\r\nm: 3\r\nA: 4\r\n\r\n";
+    let expected = vec![("Z", "1"), ("A", "2"), ("m", "3"), ("A", "4")];
+    let query = http::HttpQuery::from_string(req).unwrap();
+    assert_eq!(query.headers_in_order().collect::<Vec<_>>(), expected);
+    let query = http::HttpQuery::from_string_owned(req).unwrap();
+    assert_eq!(query.headers_in_order().collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn header_whitespace() {
+    let query = http::HttpQuery::from_string(b"GET / HTTP/1.1\r\nHost:   example.com  \r\ntype: lol\r\nX-Tab:\tx\ty\t\r\nX-Empty: \r\n\r\n").unwrap();
+    assert_eq!(query.header("Host"), Some("example.com"));
+    assert_eq!(query.header("type"), Some("lol"));
+    assert_eq!(query.header("X-Tab"), Some("x\ty"));
+    assert_eq!(query.header("X-Empty"), Some(""));
+
+    assert!(matches!(http::HttpQuery::from_string(b"GET / HTTP/1.1\r\nHo st: example.com\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(http::HttpQuery::from_string(b"GET / HTTP/1.1\r\nHost : example.com\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(http::HttpQuery::from_string(b"GET / HTTP/1.1\r\n: example.com\r\n\r\n"), Err(ParserError::InvalidData)));
+}
+
+#[test]
+fn unknown_verb() {
+    let query = http::HttpQuery::from_string(b"FOOBAR /x HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.verb, http::HTTPVerb::Unknown("FOOBAR".into()));
+    assert_ne!(query.verb, http::HTTPVerb::GET);
+    let query = http::HttpQuery::from_string(b"DELETE /x HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.verb, http::HTTPVerb::DELETE);
+    // methods are case-sensitive
+    let query = http::HttpQuery::from_string(b"get /x HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.verb, http::HTTPVerb::Unknown("get".into()));
+
+    assert!(matches!(http::HttpQuery::from_string(b"G(E)T /x HTTP/1.1\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(http::HttpQuery::from_string(b" /x HTTP/1.1\r\n\r\n"), Err(ParserError::InvalidData)));
+    assert!(matches!(http::HttpQuery::from_string(b"G\xc3\xa9T /x HTTP/1.1\r\n\r\n"), Err(ParserError::InvalidData)));
+}
+
+#[test]
+fn verb_round_trip() {
+    use http::HTTPVerb::*;
+    for verb in [GET, POST, PUT, HEAD, DELETE, OPTIONS, TRACE, CONNECT].iter() {
+        assert_eq!(http::HTTPVerb::parse_from_utf8(verb.as_str().as_bytes()), Some(verb.clone()));
+        assert_eq!(verb.to_string(), verb.as_str());
+    }
+    assert_eq!(GET.to_string(), "GET");
+    assert_eq!(http::HTTPVerb::parse_from_utf8(b"get"), None);
+
+    // the unknown methods are kept as they were sent
+    let verb = http::HttpQuery::from_string(b"PATCH /x HTTP/1.1\r\n\r\n").unwrap().verb;
+    assert_eq!(verb, Unknown("PATCH".into()));
+    assert_eq!(format!("{} /x", verb), "PATCH /x");
+    assert_eq!(http::HTTPVerb::parse_from_utf8(verb.as_str().as_bytes()), None);
+}
+
+#[test]
+fn http_version() {
+    let query = http::HttpQuery::from_string(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    assert_eq!(query.version, http::HttpVersion::Http10);
+    assert!(!query.version.keep_alive_by_default());
+    let query = http::HttpQuery::from_string(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.version, http::HttpVersion::Http11);
+    assert!(query.version.keep_alive_by_default());
+    let query = http::HttpQuery::from_string(b"GET / HTTP/1.2\r\n\r\n").unwrap();
+    assert_eq!(query.version, http::HttpVersion::Http11);
+
+    for req in [&b"GET / HTTP/2.0\r\n\r\n"[..], b"GET / HTTP/0.9\r\n\r\n", b"GET / HTTP/1.10\r\n\r\n", b"GET / http/1.1\r\n\r\n", b"GET / \r\n\r\n"] {
+        assert!(matches!(http::HttpQuery::from_string(req), Err(ParserError::InvalidData)), "{:?}", str::from_utf8(req));
+    }
+}
+
+#[test]
+fn parse_limits() {
+    let limits = http::ParseLimits {
+        max_headers: 10,
+        max_header_bytes: 1000,
+        max_url_len: 20,
+        max_body_bytes: 10
+    };
+    let mut req = b"GET /lol17 HTTP/1.1\r\n".to_vec();
+    for i in 0..10 {
+        req.extend_from_slice(format!("header{}: {}\r\n", i, i).as_bytes());
+    }
+    let mut full_req = req.clone();
+    full_req.extend_from_slice(b"\r\n");
+    assert_eq!(http::HttpQuery::from_string_limited(&full_req, limits).unwrap().headers.len(), 10);
+
+    // the query is rejected as soon as the extra header is seen, even though it isn't complete
+    req.extend_from_slice(b"header10: 10\r\n");
+    assert!(matches!(http::HttpQuery::from_string_limited(&req, limits), Err(ParserError::LimitExceeded(Limit::Headers))));
+    req.extend_from_slice(b"\r\n");
+    assert!(http::HttpQuery::from_string(&req).is_ok());
+
+    let req = generate_long_http_query(2, 0);
+    assert!(http::HttpQuery::from_string_limited(&req, limits).is_ok());
+    let req = generate_long_http_query(100, 0);
+    assert!(matches!(http::HttpQuery::from_string_limited(&req, http::ParseLimits { max_headers: 1000, ..limits }), Err(ParserError::LimitExceeded(Limit::Headers))));
+
+    assert!(http::HttpQuery::from_string_limited(b"GET /aaaaaaaaaaaaaaaaaaa HTTP/1.1\r\n\r\n", limits).is_ok());
+    assert!(matches!(http::HttpQuery::from_string_limited(b"GET /aaaaaaaaaaaaaaaaaaaa HTTP/1.1\r\n\r\n", limits), Err(ParserError::LimitExceeded(Limit::Url))));
+
+    // no need to wait for the end of a field that is already too long
+    assert!(matches!(http::HttpQuery::from_string_limited(b"GET /aaaaaaaaaaaaaaaaaaa", limits), Err(ParserError::InvalidState(InvalidStateError::EOF))));
+    assert!(matches!(http::HttpQuery::from_string_limited(b"GET /aaaaaaaaaaaaaaaaaaaa", limits), Err(ParserError::LimitExceeded(Limit::Url))));
+    let mut req = b"GET / HTTP/1.1\r\nheader: ".to_vec();
+    req.resize(req.len()+1000, b'a');
+    assert!(matches!(http::HttpQuery::from_string_limited(&req, limits), Err(ParserError::LimitExceeded(Limit::Headers))));
+
+    assert!(http::HttpQuery::from_string_limited(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789", limits).is_ok());
+    // the body is rejected before it arrives
+    assert!(matches!(http::HttpQuery::from_string_limited(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n", limits), Err(ParserError::LimitExceeded(Limit::Body))));
+    // the chunk sizes count too
+    let req = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n0\r\n\r\n";
+    assert!(http::HttpQuery::from_string_limited(req, http::ParseLimits { max_body_bytes: 11, ..limits }).is_ok());
+    assert!(matches!(http::HttpQuery::from_string_limited(req, limits), Err(ParserError::LimitExceeded(Limit::Body))));
+    assert!(matches!(http::HttpQuery::from_string_limited(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n20\r\n0123456789", limits), Err(ParserError::LimitExceeded(Limit::Body))));
+}
+
+#[test]
+fn response_to_bytes() {
+    let response = http::HttpResponse::ok(b"Hi, what's up ?".to_vec()).with_header("Content-Type", "text/plain");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.to_bytes(), b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 15\r\n\r\nHi, what's up ?".to_vec());
+
+    assert_eq!(http::HttpResponse::not_found().to_bytes(), b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nNot Found".to_vec());
+    // Content-Length always matches the body
+    let response = http::HttpResponse::new(299, Vec::new()).with_header("content-length", "42");
+    assert_eq!(response.to_bytes(), b"HTTP/1.1 299 \r\nContent-Length: 0\r\n\r\n".to_vec());
+}
+
+#[test]
+fn query_round_trip() {
+    let req = b"\r\nPROPFIND /a%20b?x=1 HTTP/1.0\r\nHost:  example.com\r\nX-Forwarded-For: a\r\nx-forwarded-for: b\r\n\r\n";
+    let query = http::HttpQuery::from_string(req).unwrap();
+    let bytes = query.to_bytes();
+    assert_eq!(bytes, b"PROPFIND /a%20b?x=1 HTTP/1.0\r\nHost: example.com\r\nX-Forwarded-For: a\r\nx-forwarded-for: b\r\n\r\n".to_vec());
+    let parsed = http::HttpQuery::from_string(&bytes).unwrap();
+    assert_eq!(parsed.verb, query.verb);
+    assert_eq!(parsed.version, query.version);
+    assert_eq!(parsed.url, query.url);
+    assert_eq!(parsed.headers, query.headers);
+    assert_eq!(parsed.body, query.body);
+
+    // chunked bodies are sent whole
+    let req = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nType: lol\r\n\r\n3\r\nabc\r\n1\r\nd\r\n0\r\n\r\n";
+    let query = http::HttpQuery::from_string(req).unwrap();
+    let bytes = query.to_bytes();
+    assert_eq!(bytes, b"POST / HTTP/1.1\r\nType: lol\r\nContent-Length: 4\r\n\r\nabcd".to_vec());
+    let parsed = http::HttpQuery::from_string(&bytes).unwrap();
+    assert_eq!(*parsed.body, *b"abcd");
+    assert_eq!(parsed.trailing, b"");
+}
+
+#[test]
+fn leading_crlf() {
+    let query = http::HttpQuery::from_string(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.url, "/a");
+    let query = http::HttpQuery::from_string(b"\r\n\r\n\n\rGET /b HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.verb, http::HTTPVerb::GET);
+    assert_eq!(query.url, "/b");
+    let query = http::HttpQuery::from_string(b"\nG /c HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(query.verb, http::HTTPVerb::Unknown("G".into()));
+
+    for req in [&b"\r\n\r\n"[..], b"\n", b""] {
+        assert!(matches!(http::HttpQuery::from_string(req), Err(ParserError::InvalidState(InvalidStateError::EOF))));
+    }
+}
+
+#[test]
+fn parse_invalid_utf8() {
+    match http::HttpQuery::from_string(b"GET /lol17 HTTP/1.1\r\ntype: l\xffl\r\n\r\n") {
+        Err(ParserError::UTFError(e)) => assert_eq!(e.valid_up_to(), 2),
+        x => panic!("unexpected result: {:?}", x)
+    }
+    assert!(matches!(http::HttpQuery::from_string(b"GET /l\xc3 HTTP/1.1\r\n\r\n"), Err(ParserError::UTFError(_))));
+}
+
+#[test]
+fn parse_owned_query() {
+    let query = {
+        let req = format!("POST /upload HTTP/1.1\r\ntype: lol\r\n\r\n{}", "data");
+        http::HttpQuery::from_string_owned(req.as_bytes()).unwrap()
+    };
+    // the query outlives its buffer and can be sent to another thread
+    let handle = thread::spawn(move || {
+        assert_eq!(query.verb, http::HTTPVerb::POST);
+        assert_eq!(query.url, "/upload");
+        assert!(query.header("Type").is_some());
+        assert_eq!(*query.body, *b"data");
+    });
+    handle.join().unwrap();
+}
 
 #[bench]
 fn bench_http_parsing(b: &mut Bencher) {
@@ -29,8 +383,7 @@ fn generate_long_http_query(headers_num: usize, garbage_size: usize) -> Vec<u8>
     let mut req = b"GET /lol17 HTTP/1.1\r\ntype: lol\r\n".to_vec();
     req.extend_from_slice(&generate_headers(headers_num));
     req.extend_from_slice(b"\r\n");
-    let mut buf = Vec::with_capacity(garbage_size);
-    buf.resize(garbage_size, 0);
+    let mut buf = vec![0; garbage_size];
     rand::thread_rng().fill_bytes(&mut buf);
     req.extend_from_slice(&buf);
     req
@@ -45,6 +398,16 @@ fn bench_http_parsing_long_100_8192(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn bench_http_parsing_long_100_8192_reuse(b: &mut Bencher) {
+    let req = generate_long_http_query(100, 8192);
+
+    let mut headers = Vec::new();
+    b.iter(|| {
+        headers = http::HttpQuery::parse_into(&req, std::mem::take(&mut headers)).unwrap().recycle_headers();
+    });
+}
+
 #[bench]
 fn bench_http_parsing_long_500_4096(b: &mut Bencher) {
     let req = generate_long_http_query(500, 4096);
@@ -61,4 +424,83 @@ fn bench_http_parsing_long_5000_65536(b: &mut Bencher) {
     b.iter(|| {
         http::HttpQuery::from_string(&req).unwrap();
     });
-}
\ No newline at end of file
+}
+
+#[test]
+fn content_negotiation() {
+    let query = http::HttpQuery::from_string(b"GET / HTTP/1.1\r\nAccept: text/html;q=0.5, application/json, text/plain;q=0.9\r\n\r\n").unwrap();
+    assert!(query.accepts("application/json"));
+    assert!(query.accepts("TEXT/HTML"));
+    assert!(!query.accepts("image/png"));
+    assert_eq!(query.preferred(&["text/html", "text/plain", "application/json"]), Some("application/json"));
+    assert_eq!(query.preferred(&["text/html", "text/plain"]), Some("text/plain"));
+    assert_eq!(query.preferred(&["image/png"]), None);
+
+    // wildcards, the most specific range wins
+    let query = http::HttpQuery::from_string(b"GET / HTTP/1.1\r\nAccept: image/*;q=0.8, */*;q=0.1\r\nAccept: image/gif;q=0\r\n\r\n").unwrap();
+    assert!(query.accepts("image/png"));
+    assert!(query.accepts("text/html"));
+    assert!(!query.accepts("image/gif"));
+    assert_eq!(query.preferred(&["text/html", "image/gif", "image/png"]), Some("image/png"));
+    assert_eq!(query.preferred(&["text/html", "application/json"]), Some("text/html"));
+
+    // no Accept header: everything goes
+    let query = http::HttpQuery::from_string(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert!(query.accepts("image/png"));
+    assert_eq!(query.preferred(&["text/html", "image/png"]), Some("text/html"));
+    assert_eq!(query.preferred(&[]), None);
+}
+
+#[test]
+fn parse_pipelined() {
+    let buf = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\n\r\n\r\nGET /c HTTP/1.1\r\n\r\n";
+    let queries = http::HttpQuery::parse_all(buf).unwrap();
+    assert_eq!(queries.iter().map(|q| q.path).collect::<Vec<_>>(), vec!["/a", "/b", "/c"]);
+    assert!(queries.iter().all(|q| q.body.is_empty()));
+    assert_eq!(queries[1].header("Host"), Some("x"));
+
+    // the bodies end where their framing says
+    let buf = b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcPOST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nde\r\n0\r\n\r\nGET /c HTTP/1.1\r\n\r\n";
+    let queries = http::HttpQuery::parse_all(buf).unwrap();
+    assert_eq!(queries.iter().map(|q| &q.body[..]).collect::<Vec<_>>(), vec![&b"abc"[..], b"de", b""]);
+
+    assert!(http::HttpQuery::parse_all(b"").unwrap().is_empty());
+    // the last query hasn't been received entirely
+    assert!(matches!(http::HttpQuery::parse_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nHost"),
+        Err(ParserError::InvalidState(InvalidStateError::EOF))));
+    assert!(matches!(http::HttpQuery::parse_all(b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 3\r\n\r\nab"),
+        Err(ParserError::InvalidState(InvalidStateError::EOF))));
+}
+
+#[test]
+fn keep_alive() {
+    let keep_alive = |q: &[u8]| http::HttpQuery::from_string(q).unwrap().keep_alive();
+    assert!(keep_alive(b"GET / HTTP/1.1\r\n\r\n"));
+    assert!(!keep_alive(b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"));
+    assert!(!keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+    assert!(keep_alive(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"));
+    assert!(!keep_alive(b"GET / HTTP/1.1\r\nConnection: upgrade, close\r\n\r\n"));
+}
+
+#[test]
+fn parse_into() {
+    let mut headers = Vec::new();
+    for i in 0..3 {
+        let req = format!("GET /{} HTTP/1.1\r\nA: {}\r\nB: b\r\n\r\n", i, i);
+        let query = http::HttpQuery::parse_into(req.as_bytes(), headers).unwrap();
+        assert_eq!(query.headers, vec![("A", &*i.to_string()), ("B", "b")]);
+        assert_eq!(query.path, format!("/{}", i));
+        headers = query.recycle_headers();
+        assert!(headers.is_empty());
+    }
+
+    // the vector is cleared, but its memory is reused
+    let mut headers = Vec::with_capacity(16);
+    headers.push(("stale", "header"));
+    let ptr = headers.as_ptr() as usize;
+    let query = http::HttpQuery::parse_into(b"GET / HTTP/1.1\r\nA: a\r\n\r\n", headers).unwrap();
+    assert_eq!(query.headers, vec![("A", "a")]);
+    assert_eq!(query.headers.as_ptr() as usize, ptr);
+    let headers = query.recycle_headers();
+    assert_eq!(headers.as_ptr() as usize, ptr);
+}
@@ -1,6 +1,9 @@
 use crate::lib::messagequeue::*;
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
 
 #[derive(Debug, PartialEq)]
@@ -19,16 +22,22 @@ fn send_msg(tx: &mut MessageQueueSender<usize>, num: usize) {
 #[test]
 fn create() {
     assert_eq!(MessageQueueSender::<usize>::new(0).err(), Some(MessageQueueError::UnvalidSize));
-    assert_eq!(MessageQueueSender::<usize>::new(1).err(), Some(MessageQueueError::UnvalidSize));
+    assert!(MessageQueueSender::<usize>::new(1).is_ok());
     // Attempt to create a queue to contain 10^12 messages
     // This shouldn't work until someone with much more money than myself decided to use it (or the
     // kernel did some insane scheming when we weren't looking)
     assert_eq!(MessageQueueSender::<usize>::new(1000000000000).err(), Some(MessageQueueError::MemoryAllocationFailed));
+    // The size in bytes of this one doesn't even fit in a usize
+    assert_eq!(MessageQueueSender::<u64>::new(usize::MAX/4).err(), Some(MessageQueueError::MemoryAllocationFailed));
+    assert_eq!(MessageQueueSender::<TestStruct>::new(usize::MAX-1).err(), Some(MessageQueueError::MemoryAllocationFailed));
 
     assert!(MessageQueueSender::<&u8>::new(2048).is_ok());
     assert!(MessageQueueSender::<f64>::new(250000).is_ok());
     assert!(MessageQueueSender::<Vec<String>>::new(250000).is_ok());
     assert!(MessageQueueSender::<TestStruct>::new(250000).is_ok());
+
+    assert!(MessageQueueSender::<usize>::new_on_node(2048, 0).is_ok());
+    assert_eq!(MessageQueueSender::<usize>::new_on_node(0, 0).err(), Some(MessageQueueError::UnvalidSize));
 }
 
 #[test]
@@ -41,8 +50,8 @@ fn create_reader() {
 
 #[test]
 fn send_without_reader() {
-    let (mut tx, _) = message_queue(256).unwrap();
-    send_msg(&mut tx, 255);
+    let (mut tx, _rx) = message_queue(256).unwrap();
+    send_msg(&mut tx, 256);
     // One too much
     assert_eq!(tx.send(256).err(), Some(MessageQueueError::MessageQueueFull));
 }
@@ -60,7 +69,7 @@ fn send_with_reader() {
     assert_eq!(rx.available(), 0);
     assert!(!rx.is_ready());
 
-    send_msg(&mut tx, 255);
+    send_msg(&mut tx, 256);
     // One too much
     assert_eq!(tx.send(256).err(), Some(MessageQueueError::MessageQueueFull));
 
@@ -69,7 +78,17 @@ fn send_with_reader() {
         assert_eq!(rx.blocking_read(), Some(c));
         c += 1;
     }
-    assert_eq!(c, 255);
+    assert_eq!(c, 256);
+}
+
+#[test]
+fn single_element_queue() {
+    let (mut tx, mut rx) = message_queue(1).unwrap();
+    for i in 0..3 {
+        assert!(tx.send(i).is_ok());
+        assert_eq!(tx.send(42).err(), Some(MessageQueueError::MessageQueueFull));
+        assert_eq!(rx.read(), Some(i));
+    }
 }
 
 #[test]
@@ -92,6 +111,92 @@ fn send_struct() {
     }
 }
 
+/// Counts how many times it was dropped
+#[derive(Debug)]
+struct DropCounter(Arc<AtomicUsize>);
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// A message counting how many times each id is dropped, and owning some memory so that a
+/// double drop is a double free too
+#[derive(Debug)]
+struct Tracked {
+    id: usize,
+    payload: Box<usize>,
+    drops: Arc<Vec<AtomicUsize>>
+}
+
+impl Tracked {
+    fn new(id: usize, drops: &Arc<Vec<AtomicUsize>>) -> Tracked {
+        Tracked {
+            id,
+            payload: Box::new(id),
+            drops: drops.clone()
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        assert_eq!(*self.payload, self.id);
+        self.drops[self.id].fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn drop_unread_elements() {
+    let drops = Arc::new(AtomicUsize::new(0));
+    let mut tx = MessageQueueSender::new(8).unwrap();
+    let mut rx = tx.new_reader();
+    // wrap around the ring a few times, reusing every slot
+    for _ in 0..20 {
+        tx.send(DropCounter(drops.clone())).unwrap();
+        drop(rx.read());
+    }
+    assert_eq!(drops.load(Ordering::SeqCst), 20);
+
+    for _ in 0..5 {
+        tx.send(DropCounter(drops.clone())).unwrap();
+    }
+    drop(rx.read());
+    assert_eq!(drops.load(Ordering::SeqCst), 21);
+    drop(tx);
+    assert_eq!(drops.load(Ordering::SeqCst), 21);
+    drop(rx);
+    // the 4 messages left in the queue are dropped along with it
+    assert_eq!(drops.load(Ordering::SeqCst), 25);
+}
+
+#[derive(Debug, PartialEq)]
+#[repr(align(64))]
+struct CacheLine(u8);
+
+#[test]
+fn send_over_aligned() {
+    let (mut tx, mut rx) = message_queue(16).unwrap();
+    for i in 0..40 {
+        tx.send(CacheLine(i)).unwrap();
+        assert_eq!(rx.read(), Some(CacheLine(i)));
+    }
+}
+
+#[test]
+fn send_move_only() {
+    // DropCounter isn't Clone
+    let drops = Arc::new(AtomicUsize::new(0));
+    let (mut tx, mut rx) = message_queue(4).unwrap();
+    tx.send(DropCounter(drops.clone())).unwrap();
+    let handle = thread::spawn(move || rx.blocking_read());
+    let received = handle.join().unwrap().unwrap();
+    assert!(Arc::ptr_eq(&received.0, &drops));
+    drop(received);
+    assert_eq!(drops.load(Ordering::SeqCst), 1);
+}
+
 #[test]
 fn send_across_thread() {
     let (mut tx, mut rx) = message_queue(256).unwrap();
@@ -128,13 +233,13 @@ fn send_concurrently() {
     }).join().is_ok());
 
     let (mut tx, mut rx) = message_queue(10000).unwrap();
-    let rx2 = rx.clone();
+    let (started_tx, started_rx) = channel();
     let sender_thread = thread::spawn(move || {
         for i in 0..8192 {
             assert!(tx.send(i).is_ok());
         }
-        // yay, a spinlock ;(
-        while rx2.available() == 8192 {}
+        // wait for the receiver to start reading
+        started_rx.recv().unwrap();
 		tx.send(8888).unwrap();
     });
 
@@ -143,6 +248,9 @@ fn send_concurrently() {
         while c < 8192 {
             if rx.is_ready() {
                 assert_eq!(rx.read(), Some(c));
+                if c == 0 {
+                    started_tx.send(()).unwrap();
+                }
                 c += 1;
             }
         }
@@ -157,20 +265,20 @@ fn send_concurrently() {
 #[test]
 fn send_concurrently_blocking_read() {
     let (mut tx, mut rx) = message_queue(8192).unwrap();
-    let mut rx2 = rx.clone();
     for i in 0..4096 {
         assert!(tx.send(i).is_ok());
     }
 
-    assert!(thread::spawn(move || {
+    let mut rx = thread::spawn(move || {
         for c in 0..4096  {
             assert_eq!(rx.blocking_read(), Some(c));
         }
-    }).join().is_ok());
+        rx
+    }).join().unwrap();
 
     let now = SystemTime::now();
     let blocking_thread = thread::spawn(move || {
-        assert_eq!(rx2.blocking_read(), Some(42));
+        assert_eq!(rx.blocking_read(), Some(42));
         assert!(now.elapsed().unwrap() > Duration::from_millis(50));
     });
 
@@ -179,6 +287,670 @@ fn send_concurrently_blocking_read() {
     assert!(blocking_thread.join().is_ok());
 }
 
+#[test]
+fn blocking_send() {
+    let (mut tx, mut rx) = message_queue(4).unwrap();
+    let receiver_thread = thread::spawn(move || {
+        for c in 0..100 {
+            if c % 10 == 0 {
+                thread::sleep(Duration::from_millis(2));
+            }
+            assert_eq!(rx.blocking_read(), Some(c));
+        }
+    });
+
+    // the producer is throttled to the pace of the consumer
+    for i in 0..100 {
+        assert!(tx.blocking_send(i).is_ok());
+    }
+    assert!(receiver_thread.join().is_ok());
+}
+
+#[test]
+fn disconnected_senders() {
+    let (mut tx, mut rx) = message_queue(16).unwrap();
+    send_msg(&mut tx, 2);
+    assert!(!rx.is_disconnected());
+    drop(tx);
+    assert!(rx.is_disconnected());
+    // the remaining messages can still be read
+    assert_eq!(rx.blocking_read(), Some(0));
+    assert_eq!(rx.blocking_read(), Some(1));
+    assert_eq!(rx.blocking_read(), None);
+
+    let mut tx = MessageQueueSender::new_mpsc(16).unwrap();
+    let mut rx = tx.new_reader();
+    let mut tx2 = tx.clone();
+    let blocking_thread = thread::spawn(move || {
+        assert_eq!(rx.blocking_read(), Some(42));
+        assert_eq!(rx.blocking_read(), None);
+    });
+    drop(tx);
+    thread::sleep(Duration::from_millis(20));
+    tx2.send(42).unwrap();
+    drop(tx2);
+    assert!(blocking_thread.join().is_ok());
+}
+
+#[test]
+fn disconnected_readers() {
+    let mut tx = MessageQueueSender::new_spmc(4).unwrap();
+    let rx = tx.new_reader();
+    let rx2 = rx.clone();
+    send_msg(&mut tx, 4);
+    drop(rx);
+    assert!(!tx.is_disconnected());
+    drop(rx2);
+    assert!(tx.is_disconnected());
+    assert_eq!(tx.send(5).err(), Some(MessageQueueError::Disconnected));
+
+    // a sender blocked on a full queue gives up when the last reader leaves
+    let (mut tx, rx) = message_queue(2).unwrap();
+    send_msg(&mut tx, 2);
+    let reader_thread = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        drop(rx);
+    });
+    assert_eq!(tx.blocking_send(3).err(), Some(MessageQueueError::Disconnected));
+    assert!(reader_thread.join().is_ok());
+
+    // creating a new reader reconnects the queue
+    let _rx = tx.new_reader();
+    assert!(!tx.is_disconnected());
+}
+
+#[test]
+#[should_panic(expected = "only multi-consumer queues can have several readers")]
+fn single_consumer_clone() {
+    let (_tx, rx) = message_queue::<String>(4).unwrap();
+    let _rx2 = rx.clone();
+}
+
+#[test]
+#[should_panic(expected = "only multi-consumer queues can have several readers")]
+fn single_consumer_new_reader() {
+    let (mut tx, _rx) = message_queue::<String>(4).unwrap();
+    let _rx2 = tx.new_reader();
+}
+
+/// Number of times the calling thread went to sleep so far
+#[cfg(target_os = "linux")]
+fn voluntary_switches() -> usize {
+    std::fs::read_to_string("/proc/thread-self/status").unwrap().lines()
+        .find_map(|line| line.strip_prefix("voluntary_ctxt_switches:"))
+        .unwrap().trim().parse().unwrap()
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn blocking_read_wakeups() {
+    let (mut tx, mut rx) = message_queue(4).unwrap();
+    let reader = thread::spawn(move || {
+        let start = voluntary_switches();
+        let mut read = 0;
+        while rx.blocking_read().is_some() {
+            read += 1;
+        }
+        (read, voluntary_switches()-start)
+    });
+    for i in 0..5 {
+        // give the reader the time to go to sleep
+        thread::sleep(Duration::from_millis(50));
+        tx.send(i).unwrap();
+    }
+    drop(tx);
+
+    // the reader sleeps until woken up by each message, the old backing off algorithm woke up
+    // every 500µs at most to check the queue
+    let (read, switches) = reader.join().unwrap();
+    assert_eq!(read, 5);
+    assert!(switches < 20, "the reader went to sleep {} times", switches);
+}
+
+#[test]
+fn read_timeout() {
+    let (mut tx, mut rx) = message_queue(4).unwrap();
+    let start = Instant::now();
+    assert_eq!(rx.read_timeout(Duration::from_millis(20)), Err(MessageQueueError::TimedOut));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(20));
+    // the deadline mustn't be missed by much, but the scheduler may be slow to wake us up
+    assert!(elapsed < Duration::from_secs(1), "waited for {:?}", elapsed);
+
+    tx.send(42).unwrap();
+    assert_eq!(rx.read_timeout(Duration::from_millis(20)), Ok(Some(42)));
+    drop(tx);
+    assert_eq!(rx.read_timeout(Duration::from_millis(20)), Ok(None));
+}
+
+#[test]
+fn capacity() {
+    let (mut tx, mut rx) = message_queue(3).unwrap();
+    assert_eq!(tx.capacity(), 3);
+    tx.hint_sequential();
+    assert!(tx.is_empty());
+    assert!(rx.is_empty());
+    for i in 0..3 {
+        assert!(!tx.is_full());
+        tx.send(i).unwrap();
+        assert!(!tx.is_empty());
+        assert!(!rx.is_empty());
+    }
+    assert!(tx.is_full());
+    assert_eq!(tx.send(3), Err(MessageQueueError::MessageQueueFull));
+    rx.read();
+    assert!(!tx.is_full());
+    rx.read();
+    rx.read();
+    assert!(tx.is_empty());
+    assert!(rx.is_empty());
+}
+
+#[test]
+fn stats() {
+    let mut tx = MessageQueueSender::new_with_stats(32).unwrap();
+    let mut rx = tx.new_reader();
+    assert_eq!(tx.stats(), QueueStats::default());
+    for round in 0..4 {
+        for i in 0..10 {
+            tx.send(round*10+i).unwrap();
+        }
+        for _ in 0..7 {
+            rx.read().unwrap();
+        }
+    }
+    let mut out = Vec::new();
+    assert_eq!(rx.read_batch(&mut out, 100), 12);
+    assert_eq!(tx.stats(), QueueStats {
+        // 3 messages are left behind every round
+        peak_occupancy: 19,
+        total_sent: 40,
+        total_read: 40
+    });
+    assert_eq!(rx.stats(), tx.stats());
+
+    // no statistics by default
+    let (mut tx, _rx) = message_queue(4).unwrap();
+    tx.send(1).unwrap();
+    assert_eq!(tx.stats(), QueueStats::default());
+}
+
+#[test]
+fn drain() {
+    let (mut tx, mut rx) = message_queue(16).unwrap();
+    for i in 0..10 {
+        tx.send(i).unwrap();
+    }
+    assert_eq!(rx.drain().collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+    assert!(rx.is_empty());
+    assert_eq!(rx.drain().next(), None);
+}
+
+#[test]
+fn read_window() {
+    let (mut tx, mut rx) = message_queue(4).unwrap();
+    assert!(rx.read_window().is_none());
+    for i in 0..3 {
+        tx.send(i).unwrap();
+    }
+    assert_eq!(*rx.read_window().unwrap(), [0, 1, 2]);
+    assert!(rx.is_empty());
+
+    // the messages wrap around the end of the ring
+    for i in 3..7 {
+        tx.send(i).unwrap();
+    }
+    assert_eq!(rx.available(), 4);
+    {
+        let mut window = rx.read_window().unwrap();
+        assert_eq!(*window, [3, 4]);
+        window[1] = 42;
+    }
+    assert_eq!(rx.available(), 2);
+    assert_eq!(*rx.read_window().unwrap(), [5, 6]);
+    assert!(rx.read_window().is_none());
+
+    // the messages are dropped along with the window
+    let drops = Arc::new(AtomicUsize::new(0));
+    let (mut tx, mut rx) = message_queue(4).unwrap();
+    for _ in 0..3 {
+        tx.send(DropCounter(drops.clone())).unwrap();
+    }
+    let window = rx.read_window().unwrap();
+    assert_eq!(window.len(), 3);
+    assert_eq!(drops.load(Ordering::SeqCst), 0);
+    drop(window);
+    assert_eq!(drops.load(Ordering::SeqCst), 3);
+    drop(rx);
+    assert_eq!(drops.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn read_window_cloned_reader() {
+    // a window lends its messages in place: no other reader may take them in the meantime
+    let (mut tx, rx) = message_queue::<String>(4).unwrap();
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| rx.clone())).is_err());
+    let mut rx = rx;
+    tx.send("lol".to_string()).unwrap();
+    assert_eq!(*rx.read_window().unwrap(), ["lol".to_string()]);
+
+    // the readers of a multi-consumer queue can be cloned, but don't lend their messages
+    let mut tx = MessageQueueSender::new_spmc(4).unwrap();
+    let mut rx = tx.new_reader();
+    let _rx2 = rx.clone();
+    tx.send("lol".to_string()).unwrap();
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| rx.read_window().is_some())).is_err());
+}
+
+#[test]
+fn available_contiguous() {
+    let (mut tx, mut rx) = message_queue(8).unwrap();
+    assert_eq!(rx.available_contiguous(), 0);
+    for i in 0..6 {
+        tx.send(i).unwrap();
+    }
+    assert_eq!(rx.available_contiguous(), 6);
+    for _ in 0..5 {
+        rx.read();
+    }
+    // 7 messages from slot 5 of a 9 slots ring
+    for i in 6..12 {
+        tx.send(i).unwrap();
+    }
+    assert_eq!(rx.available(), 7);
+    assert_eq!(rx.available_contiguous(), 4);
+    assert_eq!(rx.read_window().unwrap().len(), 4);
+    assert_eq!(rx.available_contiguous(), 3);
+    assert_eq!(rx.available(), 3);
+}
+
+#[test]
+fn lossy() {
+    let mut tx = MessageQueueSender::new_lossy(4).unwrap();
+    let mut rx = tx.new_reader();
+    for i in 0..10 {
+        tx.send_or_overwrite(i);
+    }
+    // only the newest messages are left
+    assert_eq!(rx.available(), 4);
+    assert_eq!(rx.drain().collect::<Vec<_>>(), vec![6, 7, 8, 9]);
+    tx.send_or_overwrite(10);
+    assert_eq!(rx.read(), Some(10));
+    assert_eq!(rx.read(), None);
+
+    // every message is dropped exactly once, either by the sender or by the reader
+    let drops = Arc::new(AtomicUsize::new(0));
+    let mut tx = MessageQueueSender::new_lossy(4).unwrap();
+    let mut rx = tx.new_reader();
+    let sender_drops = drops.clone();
+    let sender = thread::spawn(move || {
+        for i in 0..100000 {
+            tx.send_or_overwrite((i, DropCounter(sender_drops.clone())));
+        }
+    });
+    let mut last = None;
+    while let Some((i, _)) = rx.blocking_read() {
+        // some messages are lost, but the order is kept
+        assert!(last < Some(i));
+        last = Some(i);
+    }
+    sender.join().unwrap();
+    assert_eq!(last, Some(99999));
+    assert_eq!(drops.load(Ordering::SeqCst), 100000);
+}
+
+#[test]
+fn lossy_single_slot() {
+    // with a single slot, the positions come back to the same slot at every message, so a stale
+    // claim would be caught by a double drop
+    const MESSAGES: usize = 200000;
+    let drops = Arc::new((0..MESSAGES).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+    let mut tx = MessageQueueSender::new_lossy(1).unwrap();
+    let mut rx = tx.new_reader();
+    // the reader races with the sender dropping the message it is claiming
+    let reader = thread::spawn(move || {
+        let mut last = None;
+        while let Some(msg) = rx.blocking_read() {
+            let msg: Tracked = msg;
+            assert!(last < Some(msg.id));
+            last = Some(msg.id);
+        }
+    });
+    for id in 0..MESSAGES {
+        tx.send_or_overwrite(Tracked::new(id, &drops));
+    }
+    drop(tx);
+    reader.join().unwrap();
+    assert!(drops.iter().all(|x| x.load(Ordering::SeqCst) == 1));
+}
+
+#[test]
+#[should_panic]
+fn lossy_peek() {
+    let mut tx = MessageQueueSender::new_lossy(4).unwrap();
+    let rx = tx.new_reader();
+    tx.send_or_overwrite(1);
+    rx.peek();
+}
+
+#[test]
+fn clear() {
+    let drops = Arc::new(AtomicUsize::new(0));
+    let mut tx = MessageQueueSender::new(4).unwrap();
+    let mut rx = tx.new_reader();
+    // wrap around the end of the ring first
+    for _ in 0..3 {
+        tx.send(DropCounter(drops.clone())).unwrap();
+        drop(rx.read());
+    }
+    for _ in 0..4 {
+        tx.send(DropCounter(drops.clone())).unwrap();
+    }
+    assert!(tx.is_full());
+    unsafe { tx.clear(); }
+    assert_eq!(drops.load(Ordering::SeqCst), 7);
+    assert_eq!(rx.available(), 0);
+    assert!(rx.read().is_none());
+
+    // the whole capacity is available again
+    for _ in 0..4 {
+        tx.send(DropCounter(drops.clone())).unwrap();
+    }
+    assert!(tx.is_full());
+    assert_eq!(rx.available(), 4);
+    assert_eq!(rx.drain().count(), 4);
+    assert_eq!(drops.load(Ordering::SeqCst), 11);
+
+    // on an empty queue
+    unsafe { tx.clear(); }
+    assert!(rx.is_empty());
+}
+
+#[test]
+fn wait_any_queue() {
+    let (mut tx1, mut rx1) = message_queue(4).unwrap();
+    let (mut tx2, mut rx2) = message_queue(4).unwrap();
+    let (_tx3, mut rx3) = message_queue::<usize>(4).unwrap();
+
+    // nothing ever arrives
+    let start = Instant::now();
+    assert_eq!(wait_any(&mut [&mut rx1, &mut rx2, &mut rx3], Some(Duration::from_millis(20))), None);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    // both queues are ready, the first one wins
+    tx2.send(2).unwrap();
+    tx1.send(1).unwrap();
+    assert_eq!(wait_any(&mut [&mut rx3, &mut rx2, &mut rx1], None), Some(1));
+    assert_eq!(wait_any(&mut [&mut rx1, &mut rx2], Some(Duration::from_millis(20))), Some(0));
+
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        tx1.send(3).unwrap();
+    });
+    rx1.read();
+    rx2.read();
+    assert_eq!(wait_any(&mut [&mut rx3, &mut rx2, &mut rx1], None), Some(2));
+    assert_eq!(rx1.read(), Some(3));
+    handle.join().unwrap();
+}
+
+#[test]
+fn grow() {
+    let (mut tx, mut rx) = message_queue(4).unwrap();
+    for i in 0..4 {
+        tx.send(i.to_string()).unwrap();
+    }
+    assert_eq!(tx.send("4".into()), Err(MessageQueueError::MessageQueueFull));
+    unsafe {
+        assert_eq!(tx.grow(2), Err(MessageQueueError::UnvalidSize));
+        tx.grow(8).unwrap();
+    }
+    assert_eq!(tx.capacity(), 8);
+    for i in 4..8 {
+        tx.send(i.to_string()).unwrap();
+    }
+
+    // grow again while the messages wrap around the end of the ring
+    for i in 0..6 {
+        assert_eq!(rx.read(), Some(i.to_string()));
+    }
+    for i in 8..14 {
+        tx.send(i.to_string()).unwrap();
+    }
+    assert!(tx.is_full());
+    unsafe {
+        tx.grow(10000).unwrap();
+    }
+    for i in 14..5000 {
+        tx.send(i.to_string()).unwrap();
+    }
+    assert_eq!(rx.drain().collect::<Vec<_>>(), (6..5000).map(|i| i.to_string()).collect::<Vec<_>>());
+}
+
+#[test]
+fn peek() {
+    let (mut tx, mut rx) = message_queue(4).unwrap();
+    assert_eq!(rx.peek(), None);
+    tx.send(String::from("first")).unwrap();
+    tx.send(String::from("second")).unwrap();
+    assert_eq!(rx.peek().map(String::as_str), Some("first"));
+    // peeking doesn't consume the message
+    assert_eq!(rx.peek().map(String::as_str), Some("first"));
+    assert_eq!(rx.available(), 2);
+    assert_eq!(rx.read().as_deref(), Some("first"));
+    assert_eq!(rx.peek().map(String::as_str), Some("second"));
+    assert_eq!(rx.read().as_deref(), Some("second"));
+    assert_eq!(rx.peek(), None);
+    assert_eq!(rx.peek_mut(), None);
+
+    tx.send(String::from("third")).unwrap();
+    rx.peek_mut().unwrap().push_str(" and a half");
+    assert_eq!(rx.read().as_deref(), Some("third and a half"));
+}
+
+#[test]
+fn peek_cloned_reader() {
+    let (mut tx, rx) = message_queue(4).unwrap();
+    tx.send(String::from("first")).unwrap();
+    let first = rx.peek().unwrap();
+    // no other reader can read the message while it is borrowed
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| rx.clone().read())).is_err());
+    assert_eq!(first, "first");
+}
+
+#[test]
+fn batches() {
+    let (mut tx, mut rx) = message_queue(8).unwrap();
+    let vals: Vec<usize> = (0..10).collect();
+    assert_eq!(tx.send_slice(&vals[..5]), Ok(5));
+    // partial write
+    assert_eq!(tx.send_slice(&vals[5..]), Ok(3));
+    assert_eq!(tx.send_slice(&vals[8..]), Ok(0));
+    assert_eq!(rx.available(), 8);
+
+    let mut out = Vec::new();
+    assert_eq!(rx.read_batch(&mut out, 3), 3);
+    assert_eq!(out, vec![0, 1, 2]);
+    // the rest of the batch wraps around the end of the ring
+    assert_eq!(tx.send_slice(&vals[8..]), Ok(2));
+    assert_eq!(rx.read_batch(&mut out, 100), 7);
+    assert_eq!(out, vals);
+    assert_eq!(rx.read_batch(&mut out, 100), 0);
+    assert_eq!(tx.send_slice(&[]), Ok(0));
+
+    let mut tx = MessageQueueSender::new_mpsc(8).unwrap();
+    let mut rx = tx.new_reader();
+    let mut tx2 = tx.clone();
+    assert_eq!(tx.send_slice(&vals[..5]), Ok(5));
+    assert_eq!(tx2.send_slice(&vals[5..]), Ok(3));
+    out.clear();
+    assert_eq!(rx.read_batch(&mut out, 100), 8);
+    assert_eq!(out, &vals[..8]);
+}
+
+#[test]
+fn multiple_senders() {
+    const SENDERS: usize = 4;
+    const MESSAGES: usize = 20000;
+
+    let mut tx = MessageQueueSender::new_mpsc(512).unwrap();
+    let mut rx = tx.new_reader();
+    let mut senders = Vec::new();
+    for id in 0..SENDERS {
+        let mut tx = tx.clone();
+        senders.push(thread::spawn(move || {
+            for i in 0..MESSAGES {
+                while tx.send(id*MESSAGES+i) == Err(MessageQueueError::MessageQueueFull) {
+                    thread::yield_now();
+                }
+            }
+        }));
+    }
+
+    let mut last = vec![None; SENDERS];
+    let mut received = 0;
+    while received < SENDERS*MESSAGES {
+        if let Some(x) = rx.read() {
+            // each sender's messages arrive in order, exactly once
+            let (id, i) = (x/MESSAGES, x%MESSAGES);
+            assert_eq!(last[id].map_or(0, |x| x+1), i);
+            last[id] = Some(i);
+            received += 1;
+        }
+    }
+    assert!(!rx.is_ready());
+    assert_eq!(last, vec![Some(MESSAGES-1); SENDERS]);
+    for th in senders {
+        assert!(th.join().is_ok());
+    }
+}
+
+#[test]
+fn multi_consumer() {
+    let mut tx = MessageQueueSender::new_spmc(64).unwrap();
+    let rx = tx.new_reader();
+    let readers: Vec<_> = (0..4).map(|_| {
+        let mut rx = rx.clone();
+        thread::spawn(move || {
+            let mut got = Vec::new();
+            while let Some(x) = rx.blocking_read() {
+                got.push(x);
+            }
+            got
+        })
+    }).collect();
+    drop(rx);
+    for i in 0..100000 {
+        tx.blocking_send(i).unwrap();
+    }
+    drop(tx);
+    // every message was read by exactly one reader, in order
+    let mut all = Vec::new();
+    for reader in readers {
+        let got = reader.join().unwrap();
+        assert!(got.windows(2).all(|x| x[0] < x[1]));
+        all.extend(got);
+    }
+    all.sort();
+    assert_eq!(all, (0..100000).collect::<Vec<usize>>());
+}
+
+#[test]
+fn multi_consumer_single_slot() {
+    // the readers keep racing for the same slot, each message must still be moved out (and
+    // dropped) exactly once
+    const MESSAGES: usize = 100000;
+    let drops = Arc::new((0..MESSAGES).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+    let mut tx = MessageQueueSender::new_spmc(1).unwrap();
+    let rx = tx.new_reader();
+    let readers: Vec<_> = (0..8).map(|_| {
+        let mut rx = rx.clone();
+        thread::spawn(move || {
+            let mut count = 0;
+            while let Some(msg) = rx.blocking_read() {
+                let msg: Tracked = msg;
+                assert!(msg.id < MESSAGES);
+                count += 1;
+            }
+            count
+        })
+    }).collect();
+    drop(rx);
+    for id in 0..MESSAGES {
+        tx.blocking_send(Tracked::new(id, &drops)).unwrap();
+    }
+    drop(tx);
+    let read: usize = readers.into_iter().map(|reader| reader.join().unwrap()).sum();
+    assert_eq!(read, MESSAGES);
+    assert!(drops.iter().all(|x| x.load(Ordering::SeqCst) == 1));
+}
+
+#[test]
+#[should_panic]
+fn multi_consumer_peek() {
+    let mut tx = MessageQueueSender::new_spmc(4).unwrap();
+    let rx = tx.new_reader();
+    tx.send(1).unwrap();
+    rx.peek();
+}
+
+#[test]
+#[should_panic]
+fn clone_single_producer_sender() {
+    let (tx, _) = message_queue::<usize>(256).unwrap();
+    let _ = tx.clone();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+struct SharedMessage {
+    id: u32,
+    value: f64
+}
+
+#[test]
+#[cfg(unix)]
+fn shared_queue() {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, getpid, ForkResult};
+
+    let name = format!("/webserv-test-{}", getpid());
+    // any bytes make a valid SharedMessage, and every process shares the layout of this build
+    let mut tx = unsafe { MessageQueueSender::<SharedMessage>::new_shared(&name, 128) }.unwrap();
+    // the name is taken
+    assert!(unsafe { MessageQueueSender::<SharedMessage>::new_shared(&name, 128) }.is_err());
+    assert_eq!(unsafe { MessageQueueReader::<u8>::attach_shared(&name) }.err(), Some(MessageQueueError::IncompatibleQueue));
+    let mut rx = unsafe { MessageQueueReader::<SharedMessage>::attach_shared(&name) }.unwrap();
+
+    match fork().unwrap() {
+        ForkResult::Child => {
+            let mut status = 0;
+            for id in 0..100 {
+                if tx.send(SharedMessage { id, value: id as f64/2. }).is_err() {
+                    status = 1;
+                }
+            }
+            // leave without running any destructor, they belong to the parent
+            unsafe { libc::_exit(status) }
+        },
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+        }
+    }
+    assert_eq!(rx.available(), 100);
+    for id in 0..100 {
+        assert_eq!(rx.read().unwrap(), SharedMessage { id, value: id as f64/2. });
+    }
+    assert!(rx.is_empty());
+
+    // the object goes away with the sender
+    tx.send(SharedMessage { id: 100, value: 0. }).unwrap();
+    drop(tx);
+    assert!(unsafe { MessageQueueReader::<SharedMessage>::attach_shared(&name) }.is_err());
+    assert_eq!(rx.read().unwrap().id, 100);
+    assert_eq!(rx.blocking_read(), None);
+}
+
 #[bench]
 fn create_message_queue_struct_50(b: &mut test::Bencher) {
     b.iter(|| MessageQueueSender::<TestStruct>::new(50).unwrap());
@@ -211,18 +983,34 @@ fn send_1k_messages(b: &mut test::Bencher) {
 	});
 }
 
+#[bench]
+fn send_1k_messages_batched(b: &mut test::Bencher) {
+    let (mut tx, mut rx) = message_queue(2048).unwrap();
+    let vals: Vec<usize> = (0..1000).collect();
+    let mut out = Vec::with_capacity(1000);
+	b.iter(|| {
+        tx.send_slice(&vals).unwrap();
+        out.clear();
+        rx.read_batch(&mut out, 1000);
+	});
+}
+
 #[bench]
 fn send_1k_messages_parallel(b: &mut test::Bencher) {
     let (mut tx, rx) = message_queue(2500).unwrap();
+    let mut rx = Some(rx);
     b.iter(|| {
-        let mut rx2 = rx.clone();
-        let th = thread::spawn(move || for _ in 0..1000 {
-            rx2.blocking_read().unwrap();
+        let mut rx2 = rx.take().unwrap();
+        let th = thread::spawn(move || {
+            for _ in 0..1000 {
+                rx2.blocking_read().unwrap();
+            }
+            rx2
         });
         for i in 0..1000 {
             tx.send(i).unwrap();
         }
-        assert!(th.join().is_ok());
+        rx = Some(th.join().unwrap());
     });
 }
 
@@ -1,3 +1,10 @@
 extern crate rand;
+mod aho;
+mod backingstore;
+#[allow(clippy::bool_assert_comparison)]
 mod messagequeue;
-mod http;
\ No newline at end of file
+mod parser;
+mod http;
+mod router;
+mod server;
+mod threadpool;
@@ -0,0 +1,247 @@
+use crate::lib::parser::*;
+
+#[test]
+fn try_or_backtracks() {
+    let input = b"abcdef";
+    // the first alternative consumes 3 bytes before failing
+    let parser = TryOr::new(Take::new(3).take(10), Take::new(2));
+    let mut state = ParserState::new();
+    assert_eq!(parser.evaluate(input, &mut state).unwrap(), OneOf::Second(&b"ab"[..]));
+    assert_eq!(ConsumerToEnd::new().evaluate(input, &mut state).unwrap(), b"cdef");
+
+    let parser = TryOr::new(Take::new(3).take(1), Take::new(2));
+    let mut state = ParserState::new();
+    assert_eq!(parser.evaluate(input, &mut state).unwrap(), OneOf::First((&b"abc"[..], &b"d"[..])));
+}
+
+#[test]
+fn run_parser() {
+    let parser = ReaderUntil::new(b",").take(1).consume_to_end();
+    assert_eq!(parse(&parser, b"ab,cd").unwrap(), ((&b"ab"[..], &b","[..]), &b"cd"[..]));
+
+    let parser = ReaderUntil::new(b",").take(1);
+    assert!(matches!(parse(&parser, b"ab,cd"), Err(ParserError::InvalidData)));
+    assert_eq!(parse_partial(&parser, b"ab,cd").unwrap(), ((&b"ab"[..], &b","[..]), 3));
+    assert!(matches!(parse(&parser, b"ab"), Err(ParserError::OutOfBoundsAccess)));
+}
+
+#[test]
+fn many() {
+    let token = ReaderUntil::new(b",").take(1);
+    let tokens = |input: &[u8]| -> Vec<Vec<u8>> {
+        parse(&Many::new(ReaderUntil::new(b",").take(1)), input).unwrap().into_iter().map(|(x, _)| x.to_vec()).collect()
+    };
+    assert_eq!(tokens(b"a,bb,ccc,"), vec![b"a".to_vec(), b"bb".to_vec(), b"ccc".to_vec()]);
+    assert_eq!(tokens(b",,"), vec![Vec::new(), Vec::new()]);
+    assert!(tokens(b"").is_empty());
+
+    // the last token isn't terminated, and is left for the next parser
+    let parser = Many::new1(token).consume_to_end();
+    let (tokens, rest) = parse(&parser, b"a,bb,ccc").unwrap();
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(tokens[1].0, b"bb");
+    assert_eq!(rest, b"ccc");
+
+    let parser = Many::new1(ReaderUntil::new(b",").take(1));
+    assert!(matches!(parse(&parser, b"abc"), Err(ParserError::OutOfBoundsAccess)));
+    assert!(parse(&parser, b"").is_err());
+    let parser = ConsumerToEnd::new().many(ReaderUntil::new(b",").take(1));
+    assert_eq!(parse(&parser, b"abc").unwrap(), (&b"abc"[..], Vec::new()));
+}
+
+#[test]
+fn number() {
+    assert_eq!(parse(&Number::new(), b"0").unwrap(), 0);
+    assert_eq!(parse(&Number::new(), b"00042").unwrap(), 42);
+    assert_eq!(parse(&Number::new(), b"18446744073709551615").unwrap(), u64::MAX);
+    assert!(matches!(parse(&Number::new(), b"18446744073709551616"), Err(ParserError::Overflow)));
+    assert!(matches!(parse(&Number::new(), b"184467440737095516150"), Err(ParserError::Overflow)));
+    assert!(matches!(parse(&Number::new(), b""), Err(ParserError::InvalidData)));
+    assert!(matches!(parse(&Number::new(), b"-1"), Err(ParserError::InvalidData)));
+    assert!(matches!(parse(&Number::new(), b"12a"), Err(ParserError::InvalidData)));
+
+    assert_eq!(parse(&Number::hex(), b"fF").unwrap(), 255);
+    assert_eq!(parse(&Number::hex(), b"ffffffffffffffff").unwrap(), u64::MAX);
+    assert!(matches!(parse(&Number::hex(), b"10000000000000000"), Err(ParserError::Overflow)));
+
+    // the number stops at the first non-digit
+    let parser = Number::new().take(1).number();
+    assert_eq!(parse(&parser, b"12,34").unwrap(), ((12, &b","[..]), 34));
+}
+
+#[test]
+fn optional() {
+    let parser = Optional::new(Number::new().take(1)).consume_to_end();
+    assert_eq!(parse(&parser, b"12 abc").unwrap(), (Some((12, &b" "[..])), &b"abc"[..]));
+
+    // absent: nothing is consumed
+    let mut state = ParserState::new();
+    assert_eq!(Optional::new(Number::new()).evaluate(b"abc", &mut state).unwrap(), None);
+    assert_eq!(parse_partial(&Optional::new(Number::new().take(10)), b"12").unwrap(), (None, 0));
+    assert_eq!(ConsumerToEnd::new().evaluate(b"abc", &mut state).unwrap(), b"abc");
+
+    // InvalidState errors are still fatal
+    assert!(matches!(parse(&Optional::new(Match::new(b"abcd")), b"ab"), Err(ParserError::InvalidState(_))));
+}
+
+#[test]
+fn state_index() {
+    let input = b"abc";
+    let mut state = ParserState::new();
+    assert_eq!(state.index(input, 0).unwrap(), b'a');
+    assert_eq!(state.index(input, 2).unwrap(), b'c');
+    assert!(matches!(state.index(input, 3), Err(ParserError::OutOfBoundsAccess)));
+    assert_eq!(state.get_current(input).unwrap(), b'a');
+
+    Take::new(2).evaluate(input, &mut state).unwrap();
+    assert_eq!(state.get_current(input).unwrap(), b'c');
+    // the index is absolute, not relative to the current position
+    assert_eq!(state.index(input, 0).unwrap(), b'a');
+    Take::new(1).evaluate(input, &mut state).unwrap();
+    assert!(matches!(state.get_current(input), Err(ParserError::OutOfBoundsAccess)));
+}
+
+#[test]
+fn read_until_consuming() {
+    let parser = ReaderUntil::consuming(b",").read_until_consuming(b",");
+    assert_eq!(parse(&parser, b"a,b,").unwrap(), (&b"a"[..], &b"b"[..]));
+
+    // the non-consuming version stops on the pattern
+    let parser = ReaderUntil::new(b",").read_until(b",");
+    assert_eq!(parse_partial(&parser, b"a,b,").unwrap(), ((&b"a"[..], &b""[..]), 1));
+
+    // a missing pattern isn't an error
+    let parser = ReaderUntil::consuming(b"\r\n").read_until_consuming(b"\r\n");
+    assert_eq!(parse(&parser, b"ab\r\ncd").unwrap(), (&b"ab"[..], &b"cd"[..]));
+}
+
+#[test]
+fn context() {
+    let parser = Context::new("size", Number::new()).context("body", Take::new(10));
+    match parse(&parser, b"12abc") {
+        Err(ParserError::Context("body", e)) => assert!(matches!(*e, ParserError::OutOfBoundsAccess)),
+        _ => panic!("expected a labelled error")
+    }
+    let parser = Context::new("request", Context::new("size", Number::new()));
+    match parse(&parser, b"abc") {
+        Err(ParserError::Context("request", e)) => match *e {
+            ParserError::Context("size", e) => assert!(matches!(*e, ParserError::InvalidData)),
+            e => panic!("unexpected error {:?}", e)
+        },
+        _ => panic!("expected a labelled error")
+    }
+
+    // the label doesn't change which errors are fatal
+    let parser = TryOr::new(Context::new("number", Number::new()), Take::new(1));
+    assert_eq!(parse(&parser, b"a").unwrap(), OneOf::Second(&b"a"[..]));
+    let parser = TryOr::new(Context::new("match", Match::new(b"abcd")), Take::new(1));
+    match parse(&parser, b"a") {
+        Err(e) => assert!(e.is_fatal()),
+        Ok(_) => panic!("EOF must not be recovered from")
+    }
+}
+
+#[test]
+fn peek() {
+    let input = b"abcdef";
+    let parser = Peeker::new(3).peek(3);
+    assert_eq!(parse_partial(&parser, input).unwrap(), ((&b"abc"[..], &b"abc"[..]), 0));
+    let parser = Take::new(3).peek(3).take(3);
+    assert_eq!(parse(&parser, input).unwrap(), ((&b"abc"[..], &b"def"[..]), &b"def"[..]));
+    assert!(matches!(parse(&Peeker::new(7), input), Err(ParserError::OutOfBoundsAccess)));
+}
+
+#[test]
+fn eof() {
+    let parser = Number::new().eof();
+    assert_eq!(parse_partial(&parser, b"42").unwrap(), ((42, ()), 2));
+    assert!(matches!(parse_partial(&parser, b"42 "), Err(ParserError::InvalidData)));
+    assert!(parse_partial(&Eof::new(), b"").is_ok());
+    // lets TryOr pick the alternative matching the whole input
+    let parser = TryOr::new(Take::new(2).eof(), ConsumerToEnd::new());
+    assert_eq!(parse(&parser, b"abc").unwrap(), OneOf::Second(&b"abc"[..]));
+}
+
+#[test]
+fn consume_with_position() {
+    // tokens of at most 4 bytes from the start of the input
+    fn first_four(data: &[u8], pos: usize) -> Result<usize, ParserError> {
+        Ok((pos < 4 && !data.is_empty()) as usize)
+    }
+    let parser = Consumer::with_pos(first_four).consume_to_end();
+    assert_eq!(parse(&parser, b"abcdef").unwrap(), (&b"abcd"[..], &b"ef"[..]));
+    assert_eq!(parse(&parser, b"ab").unwrap(), (&b"ab"[..], &b""[..]));
+
+    // the position is counted from the start of the input, not of the consumer
+    let parser = Take::new(2).consume_while_predicate_pos(first_four);
+    assert_eq!(parse_partial(&parser, b"abcdef").unwrap(), ((&b"ab"[..], &b"cd"[..]), 4));
+    let parser = Take::new(5).consume_while_predicate_pos(first_four);
+    assert_eq!(parse_partial(&parser, b"abcdef").unwrap(), ((&b"abcde"[..], &b""[..]), 5));
+}
+
+#[test]
+fn consumer_bounds() {
+    // never stops by itself
+    fn always(_: &[u8]) -> Result<usize, ParserError> {
+        Ok(1)
+    }
+    fn too_much(_: &[u8]) -> Result<usize, ParserError> {
+        Ok(3)
+    }
+    assert_eq!(parse(&Consumer::new(always), b"abcde").unwrap(), b"abcde");
+    assert_eq!(parse(&Consumer::new(always), b"").unwrap(), b"");
+    assert_eq!(parse(&Take::new(2).consume_while_predicate(always), b"abcde").unwrap(), (&b"ab"[..], &b"cde"[..]));
+
+    // consuming exactly what's left is fine, past it is not
+    assert_eq!(parse(&Consumer::new(too_much), b"abcdef").unwrap(), b"abcdef");
+    assert!(matches!(parse(&Consumer::new(too_much), b"abcde"), Err(ParserError::OutOfBoundsAccess)));
+}
+
+#[test]
+fn sequences() {
+    // a request line
+    let parser = seq3(ReaderUntil::consuming(b" "), ReaderUntil::consuming(b" "), ReaderUntil::consuming(b"\r\n"));
+    let (verb, url, version) = parse(&parser, b"GET /index.html HTTP/1.1\r\n").unwrap();
+    assert_eq!((verb, url, version), (&b"GET"[..], &b"/index.html"[..], &b"HTTP/1.1"[..]));
+
+    let parser = seq4(Number::new(), Take::new(1), Number::hex(), Optional::new(Take::new(1)));
+    assert_eq!(parse(&parser, b"12-ff").unwrap(), (12, &b"-"[..], 255, None));
+    assert!(matches!(parse(&parser, b"12-"), Err(ParserError::InvalidData)));
+}
+
+#[test]
+fn within() {
+    let name = Within::new(8, ReaderUntil::consuming(b":"));
+    assert_eq!(parse_partial(&name, b"Host: x").unwrap(), (&b"Host"[..], 5));
+    // the separator counts too
+    assert_eq!(parse_partial(&name, b"1234567:").unwrap(), (&b"1234567"[..], 8));
+    assert!(matches!(parse_partial(&name, b"12345678:"), Err(ParserError::InvalidData)));
+    // without the cap, read_until would go through the whole input
+    let long = [b"X-".to_vec(), vec![b'a'; 1000], b": x".to_vec()].concat();
+    assert!(matches!(parse_partial(&name, &long), Err(ParserError::InvalidData)));
+
+    // the position is restored on failure
+    let parser = Take::new(2).optional(Within::new(3, ConsumerToEnd::new())).consume_to_end();
+    assert_eq!(parse(&parser, b"abcdef").unwrap(), ((&b"ab"[..], None), &b"cdef"[..]));
+    assert_eq!(parse(&parser, b"abcde").unwrap(), ((&b"ab"[..], Some(&b"cde"[..])), &b""[..]));
+
+    // the parser needing more than the cap isn't an EOF
+    assert!(matches!(parse_partial(&Within::new(2, Match::new(b"abcd")), b"abcde"), Err(ParserError::InvalidData)));
+    assert!(matches!(parse_partial(&Within::new(8, Match::new(b"abcd")), b"abc"), Err(ParserError::InvalidState(_))));
+}
+
+#[test]
+fn expect() {
+    let parser = ReaderUntil::consuming(b" ").expect(b"HTTP/1.1").expect(b"\r\n");
+    assert_eq!(parse_partial(&parser, b"/ HTTP/1.1\r\nHost").unwrap(), (((&b"/"[..], ()), ()), 12));
+
+    // a mismatch doesn't move
+    let mut state = ParserState::new();
+    assert!(matches!(Expect::new(b"HTTP/1.1").evaluate(b"HTTP/2 200", &mut state), Err(ParserError::InvalidData)));
+    assert_eq!(state.pos(), 0);
+    let parser = TryOr::new(Expect::new(b"HTTP/1.1"), Expect::new(b"HTTP/2"));
+    assert_eq!(parse(&parser, b"HTTP/2").unwrap(), OneOf::Second(()));
+
+    // the rest of the pattern may not have arrived yet
+    assert!(matches!(parse(&Expect::new(b"HTTP/1.1"), b"HTTP/"), Err(ParserError::InvalidState(InvalidStateError::EOF))));
+}
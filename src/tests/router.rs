@@ -0,0 +1,76 @@
+use crate::lib::http::{HTTPVerb, HttpQuery, HttpResponse};
+use crate::lib::router::{Handler, Router};
+
+fn route<'r>(router: &'r Router<&'static str>, query: &str) -> Option<&'r str> {
+    router.route(&HttpQuery::from_string(query.as_bytes()).unwrap()).copied()
+}
+
+#[test]
+fn route_queries() {
+    let mut router = Router::new();
+    router.add(HTTPVerb::GET, "/", "index");
+    router.add(HTTPVerb::GET, "/users", "users");
+    router.add(HTTPVerb::GET, "/users/admin", "admin");
+    router.add(HTTPVerb::POST, "/users", "new user");
+
+    // exact
+    assert_eq!(route(&router, "GET / HTTP/1.1\r\n\r\n"), Some("index"));
+    assert_eq!(route(&router, "GET /users HTTP/1.1\r\n\r\n"), Some("users"));
+    assert_eq!(route(&router, "POST /users HTTP/1.1\r\n\r\n"), Some("new user"));
+    // prefix, the longest one wins
+    assert_eq!(route(&router, "GET /users/42 HTTP/1.1\r\n\r\n"), Some("users"));
+    assert_eq!(route(&router, "GET /users/admin/settings HTTP/1.1\r\n\r\n"), Some("admin"));
+    assert_eq!(route(&router, "GET /about HTTP/1.1\r\n\r\n"), Some("index"));
+    // the query string isn't part of the route
+    assert_eq!(route(&router, "GET /users?id=42 HTTP/1.1\r\n\r\n"), Some("users"));
+    // no match
+    assert_eq!(route(&router, "POST /about HTTP/1.1\r\n\r\n"), None);
+    assert_eq!(route(&router, "DELETE /users HTTP/1.1\r\n\r\n"), None);
+    assert_eq!(route(&router, "GETX /users HTTP/1.1\r\n\r\n"), None);
+    assert_eq!(route(&Router::new(), "GET / HTTP/1.1\r\n\r\n"), None);
+
+    // registering a route again replaces its handler
+    assert_eq!(router.add(HTTPVerb::GET, "/users", "other users"), Some("users"));
+    assert_eq!(route(&router, "GET /users/42 HTTP/1.1\r\n\r\n"), Some("other users"));
+}
+
+#[test]
+fn exact_routes() {
+    let mut router = Router::new();
+    router.add(HTTPVerb::GET, "/x", "x");
+    assert!(router.has_exact(HTTPVerb::GET, "/x"));
+    assert!(!router.has_exact(HTTPVerb::GET, "/x/y"));
+    assert!(!router.has_exact(HTTPVerb::GET, "/"));
+    assert_eq!(route(&router, "GET /x/y HTTP/1.1\r\n\r\n"), Some("x"));
+
+    // the path exists, but not for this verb
+    assert_eq!(route(&router, "POST /x HTTP/1.1\r\n\r\n"), None);
+    assert!(!router.has_exact(HTTPVerb::POST, "/x"));
+    let exists_for = |path: &str| [HTTPVerb::GET, HTTPVerb::POST, HTTPVerb::PUT].iter().any(|verb| router.has_exact(verb.clone(), path));
+    assert!(exists_for("/x"));
+    assert!(!exists_for("/y"));
+}
+
+#[test]
+fn dispatch() {
+    let respond = |name: &'static str| -> Handler {
+        Box::new(move |q: HttpQuery| HttpResponse::ok(format!("{} {}", name, q.path).into_bytes()))
+    };
+    let mut router = Router::new();
+    router.add(HTTPVerb::GET, "/api", respond("api"));
+    router.add(HTTPVerb::GET, "/api/v1", respond("v1"));
+    let handle = |router: &Router<Handler>, query: &str| router.handle(HttpQuery::from_string(query.as_bytes()).unwrap());
+
+    // the longest prefix wins
+    let res = handle(&router, "GET /api/v1/users HTTP/1.1\r\n\r\n");
+    assert_eq!((res.status, res.body), (200, b"v1 /api/v1/users".to_vec()));
+    let res = handle(&router, "GET /api/v2/users HTTP/1.1\r\n\r\n");
+    assert_eq!((res.status, res.body), (200, b"api /api/v2/users".to_vec()));
+
+    // nothing matches
+    assert_eq!(handle(&router, "GET /static HTTP/1.1\r\n\r\n").status, 404);
+    assert_eq!(handle(&router, "POST /api/v1 HTTP/1.1\r\n\r\n").status, 404);
+    router.set_fallback(Box::new(|_| HttpResponse::new(410, Vec::new())));
+    assert_eq!(handle(&router, "GET /static HTTP/1.1\r\n\r\n").status, 410);
+    assert_eq!(handle(&router, "GET /api HTTP/1.1\r\n\r\n").status, 200);
+}
@@ -0,0 +1,236 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::lib::http::HttpResponse;
+use crate::lib::server;
+
+fn query(addr: &str, parts: &[&[u8]]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    for part in parts {
+        stream.write_all(part).unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut res = Vec::new();
+    stream.read_to_end(&mut res).unwrap();
+    res
+}
+
+#[test]
+fn serve() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let _server = server::serve_listener(listener, |q| {
+        if q.path == "/hello" {
+            HttpResponse::ok([b"Hello ", &q.body[..]].concat())
+        } else {
+            HttpResponse::not_found()
+        }
+    }).unwrap();
+
+    let res = query(&addr, &[b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n"]);
+    assert!(res.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    assert!(res.ends_with(b"\r\n\r\nHello "));
+
+    // the query may arrive in several pieces
+    let res = query(&addr, &[b"POST /hel", b"lo HTTP/1.1\r\nConnection: close\r\nContent-Length: 5\r\n\r\nwo", b"rld"]);
+    assert!(res.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    assert!(res.ends_with(b"\r\n\r\nHello world"));
+
+    let res = query(&addr, &[b"GET /nope HTTP/1.1\r\nConnection: close\r\n\r\n"]);
+    assert!(res.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+    let res = query(&addr, &[b"GET /hello HTTP/1.1\r\nbroken\r\n\r\n"]);
+    assert!(res.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+
+    // more clients than workers at once
+    let clients: Vec<_> = (0..16).map(|_| {
+        let addr = addr.clone();
+        thread::spawn(move || query(&addr, &[b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n"]))
+    }).collect();
+    for client in clients {
+        assert!(client.join().unwrap().starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+}
+
+#[test]
+fn flood() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handled = Arc::new(AtomicUsize::new(0));
+    let handled2 = handled.clone();
+    let _server = server::serve_listener(listener, move |q| {
+        handled2.fetch_add(1, Ordering::SeqCst);
+        HttpResponse::ok(q.path.as_bytes().to_vec())
+    }).unwrap();
+
+    let clients: Vec<_> = (0..100).map(|i| {
+        let addr = addr.clone();
+        thread::spawn(move || {
+            let req = format!("GET /{} HTTP/1.1\r\nConnection: close\r\n\r\n", i);
+            let res = query(&addr, &[req.as_bytes()]);
+            res.starts_with(b"HTTP/1.1 200 OK\r\n") && res.ends_with(format!("\r\n\r\n/{}", i).as_bytes())
+        })
+    }).collect();
+    for client in clients {
+        assert!(client.join().unwrap());
+    }
+    assert_eq!(handled.load(Ordering::SeqCst), 100);
+}
+
+#[test]
+fn panicking_handler() {
+    let server = server::serve("127.0.0.1:0", |q| {
+        if q.path == "/panic" {
+            panic!("handler failed");
+        }
+        HttpResponse::ok(q.path.as_bytes().to_vec())
+    }).unwrap();
+    let addr = server.local_addr().to_string();
+    // more failures than workers, the server keeps going
+    for _ in 0..8 {
+        let res = query(&addr, &[b"GET /panic HTTP/1.1\r\n\r\n"]);
+        assert!(res.starts_with(b"HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(String::from_utf8(res).unwrap().contains("Connection: close\r\n"));
+    }
+    let res = query(&addr, &[b"GET /ok HTTP/1.1\r\nConnection: close\r\n\r\n"]);
+    assert!(res.ends_with(b"\r\n\r\n/ok"));
+}
+
+#[test]
+fn keep_alive() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let _server = server::serve_listener(listener, |q| HttpResponse::ok(q.path.as_bytes().to_vec())).unwrap();
+
+    // the connection stays open until the last query asks to close it
+    let res = query(&addr, &[b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1", b".1\r\nConnection: close\r\n\r\n"]);
+    let res = String::from_utf8(res).unwrap();
+    assert_eq!(res.matches("HTTP/1.1 200 OK\r\n").count(), 3);
+    assert_eq!(res.matches("Connection: keep-alive\r\n").count(), 2);
+    assert!(res.contains("\r\n\r\n/a"));
+    assert!(res.contains("\r\n\r\n/b"));
+    assert!(res.ends_with("Connection: close\r\nContent-Length: 2\r\n\r\n/c"));
+
+    // HTTP/1.0 closes the connection by default
+    let res = query(&addr, &[b"GET /a HTTP/1.0\r\n\r\n"]);
+    assert!(res.ends_with(b"Connection: close\r\nContent-Length: 2\r\n\r\n/a"));
+}
+
+#[test]
+fn idle_connections() {
+    let server = server::serve("127.0.0.1:0", |q| HttpResponse::ok(q.path.as_bytes().to_vec())).unwrap();
+    // keep every worker busy with a connection that doesn't send anything after its first query
+    let idle = (0..8).map(|_| {
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"GET /idle HTTP/1.1\r\n\r\n").unwrap();
+        stream
+    }).collect::<Vec<_>>();
+    thread::sleep(Duration::from_millis(100));
+
+    // they get closed long before the read timeout, letting the next client in
+    let start = Instant::now();
+    let res = query(&server.local_addr().to_string(), &[b"GET /busy HTTP/1.1\r\nConnection: close\r\n\r\n"]);
+    assert!(res.ends_with(b"\r\n\r\n/busy"));
+    assert!(start.elapsed() < Duration::from_secs(20));
+    for stream in idle {
+        let mut reader = BufReader::new(stream);
+        assert!(read_response(&mut reader).0.contains("Connection: keep-alive\r\n"));
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
+
+/// Read a single response from `stream`, leaving the connection open
+fn read_response<R: BufRead>(stream: &mut R) -> (String, Vec<u8>) {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        assert!(stream.read_line(&mut line).unwrap() > 0, "connection closed");
+        head.push_str(&line);
+        if line == "\r\n" {
+            break;
+        }
+    }
+    let length = head.lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .map_or(0, |length| length.parse().unwrap());
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).unwrap();
+    (head, body)
+}
+
+#[test]
+fn oversized_queries() {
+    let server = server::serve("127.0.0.1:0", |_| HttpResponse::ok(b"too permissive".to_vec())).unwrap();
+    let mut long_url = b"GET /".to_vec();
+    long_url.resize(10000, b'a');
+    let mut long_header = b"GET / HTTP/1.1\r\nheader: ".to_vec();
+    long_header.resize(20000, b'a');
+    let long_body = b"POST / HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n".to_vec();
+    // the server answers without waiting for the end of the query
+    for (req, status) in [(long_url, "414 URI Too Long"), (long_header, "431 Request Header Fields Too Large"), (long_body, "413 Payload Too Large")] {
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(&req).unwrap();
+        let (head, _) = read_response(&mut BufReader::new(stream));
+        assert!(head.starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{}", head);
+        assert!(head.contains("Connection: close\r\n"));
+    }
+}
+
+#[test]
+fn connection_reuse() {
+    let server = server::serve("127.0.0.1:0", |q| HttpResponse::ok([q.path.as_bytes(), &q.body[..]].concat())).unwrap();
+    let stream = TcpStream::connect(server.local_addr()).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+
+    // two pipelined queries, the first one with a body
+    writer.write_all(b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nxyzGET /b HTTP/1.1\r\n\r\n").unwrap();
+    let (head, body) = read_response(&mut reader);
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n") && head.contains("Connection: keep-alive\r\n"));
+    assert_eq!(body, b"/axyz");
+    let (head, body) = read_response(&mut reader);
+    assert!(head.contains("Connection: keep-alive\r\n"));
+    assert_eq!(body, b"/b");
+
+    // the connection is still usable once the client has read the answers
+    writer.write_all(b"GET /c HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+    let (head, body) = read_response(&mut reader);
+    assert!(head.contains("Connection: keep-alive\r\n"));
+    assert_eq!(body, b"/c");
+
+    writer.write_all(b"GET /d HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+    let (head, body) = read_response(&mut reader);
+    assert!(head.contains("Connection: close\r\n"));
+    assert_eq!(body, b"/d");
+    // and then closed
+    let mut rest = Vec::new();
+    assert_eq!(reader.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn shutdown() {
+    let server = server::serve("127.0.0.1:0", |q| HttpResponse::ok(q.path.as_bytes().to_vec())).unwrap();
+    let addr = server.local_addr().to_string();
+    let res = query(&addr, &[b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\n"]);
+    assert!(res.ends_with(b"\r\n\r\n/a"));
+
+    // an idle keep-alive connection doesn't hold the server up
+    let mut idle = TcpStream::connect(&addr).unwrap();
+    idle.write_all(b"GET /b HTTP/1.1\r\n\r\n").unwrap();
+    let mut res = [0; 256];
+    assert!(idle.read(&mut res).unwrap() > 0);
+
+    server.shutdown();
+    let (tx, rx) = channel();
+    thread::spawn(move || tx.send(server.join()).unwrap());
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap().is_ok());
+    // the idle connection was closed
+    assert_eq!(idle.read(&mut res).unwrap(), 0);
+    assert!(TcpStream::connect(&addr).is_err());
+}
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use crate::lib::messagequeue::MessageQueueError;
+use crate::lib::threadpool::ThreadPool;
+
+#[test]
+fn run_jobs() {
+    assert_eq!(ThreadPool::<usize>::new(0, 16, |_| ()).err(), Some(MessageQueueError::UnvalidSize));
+
+    let done = Arc::new(AtomicUsize::new(0));
+    let sum = Arc::new(AtomicUsize::new(0));
+    let (done2, sum2) = (done.clone(), sum.clone());
+    let mut pool = ThreadPool::new(4, 16, move |x: usize| {
+        sum2.fetch_add(x, Ordering::SeqCst);
+        done2.fetch_add(1, Ordering::SeqCst);
+    }).unwrap();
+    assert_eq!(pool.workers(), 4);
+    // way more jobs than the backlog holds
+    for i in 0..10000 {
+        pool.execute(i).unwrap();
+    }
+    // every job given is done before shutdown returns
+    pool.shutdown();
+    assert_eq!(done.load(Ordering::SeqCst), 10000);
+    assert_eq!(sum.load(Ordering::SeqCst), 10000*9999/2);
+}
+
+#[test]
+fn jobs_spread_across_workers() {
+    // each job waits for the others, so they can only complete if they all run at once
+    let started = Arc::new(AtomicUsize::new(0));
+    let started2 = started.clone();
+    let mut pool = ThreadPool::new(4, 4, move |_: usize| {
+        started2.fetch_add(1, Ordering::SeqCst);
+        while started2.load(Ordering::SeqCst) < 4 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }).unwrap();
+    for _ in 0..4 {
+        pool.execute(0).unwrap();
+    }
+    drop(pool);
+    assert_eq!(started.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn panicking_jobs() {
+    let done = Arc::new(AtomicUsize::new(0));
+    let done2 = done.clone();
+    let mut pool = ThreadPool::new(2, 4, move |x: usize| {
+        if x.is_multiple_of(2) {
+            panic!("job {} failed", x);
+        }
+        done2.fetch_add(1, Ordering::SeqCst);
+    }).unwrap();
+    // many more panics than workers
+    for i in 0..100 {
+        pool.execute(i).unwrap();
+    }
+    pool.shutdown();
+    assert_eq!(done.load(Ordering::SeqCst), 50);
+}